@@ -0,0 +1,186 @@
+// Utilities for preparing datasets of features and targets.
+
+use crate::value::{constant, Value};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+// A set of examples, as parallel vectors of features and targets.
+pub type Dataset<X, Y> = (Vec<X>, Vec<Y>);
+
+// The indices 0..length in a random order determined by `seed`.
+fn shuffled_indices(length: usize, seed: u64) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..length).collect();
+    indices.shuffle(&mut StdRng::seed_from_u64(seed));
+    indices
+}
+
+fn select<X: Clone, Y: Clone>(features: &[X], targets: &[Y], indices: &[usize]) -> Dataset<X, Y> {
+    (
+        indices.iter().map(|i| features[*i].clone()).collect(),
+        indices.iter().map(|i| targets[*i].clone()).collect(),
+    )
+}
+
+// Split the dataset into `k` folds after shuffling it with `seed`, yielding
+// a (train, validation) pair for each fold. Each fold is used as the
+// validation set exactly once, with the remaining folds as the training set.
+// When the dataset doesn't divide evenly, fold sizes differ by at most one.
+pub fn kfold<'a, X: Clone, Y: Clone>(
+    features: &'a [X],
+    targets: &'a [Y],
+    k: usize,
+    seed: u64,
+) -> impl Iterator<Item = (Dataset<X, Y>, Dataset<X, Y>)> + 'a {
+    assert_eq!(
+        features.len(),
+        targets.len(),
+        "expected one target per example"
+    );
+    assert!(
+        k > 0 && k <= features.len(),
+        "k must be between 1 and the number of examples, got {}",
+        k
+    );
+    let indices = shuffled_indices(features.len(), seed);
+    (0..k).map(move |fold| {
+        let start = fold * indices.len() / k;
+        let end = (fold + 1) * indices.len() / k;
+        let train: Vec<usize> = indices[..start]
+            .iter()
+            .chain(&indices[end..])
+            .copied()
+            .collect();
+        (
+            select(features, targets, &train),
+            select(features, targets, &indices[start..end]),
+        )
+    })
+}
+
+// Shuffle the dataset with `seed` and partition it into
+// (train_features, train_targets, test_features, test_targets), where the
+// test set holds `test_fraction` of the examples, rounded to the nearest
+// example. A fraction of 0.0 puts everything in the training set and 1.0
+// puts everything in the test set.
+pub fn train_test_split<X: Clone, Y: Clone>(
+    features: &[X],
+    targets: &[Y],
+    test_fraction: f32,
+    seed: u64,
+) -> (Vec<X>, Vec<Y>, Vec<X>, Vec<Y>) {
+    assert_eq!(
+        features.len(),
+        targets.len(),
+        "expected one target per example"
+    );
+    assert!(
+        (0.0..=1.0).contains(&test_fraction),
+        "test_fraction must be between 0 and 1, got {}",
+        test_fraction
+    );
+    let indices = shuffled_indices(features.len(), seed);
+    let test_size = (features.len() as f32 * test_fraction).round() as usize;
+    let (test, train) = indices.split_at(test_size);
+    let (train_x, train_y) = select(features, targets, train);
+    let (test_x, test_y) = select(features, targets, test);
+    (train_x, train_y, test_x, test_y)
+}
+
+// A vector of `num_classes` values which are all 0 except for a 1 at `index`.
+pub fn one_hot(index: usize, num_classes: usize) -> Vec<Value> {
+    assert!(
+        index < num_classes,
+        "index {} is out of range for {} classes",
+        index,
+        num_classes
+    );
+    (0..num_classes)
+        .map(|i| constant(if i == index { 1.0 } else { 0.0 }))
+        .collect()
+}
+
+// The index of the largest value. Ties are broken in favor of the first one.
+pub fn argmax(values: &[Value]) -> usize {
+    assert!(!values.is_empty(), "argmax of an empty slice");
+    let mut best = 0;
+    for (i, v) in values.iter().enumerate() {
+        if v.data() > values[best].data() {
+            best = i;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn kfold_validation_sets_partition_the_data() {
+        let features: Vec<usize> = (0..10).collect();
+        let targets: Vec<usize> = (0..10).map(|x| x * 10).collect();
+        let mut seen = HashSet::new();
+        let mut folds = 0;
+        for ((train_x, train_y), (validation_x, validation_y)) in kfold(&features, &targets, 5, 1) {
+            folds += 1;
+            assert_eq!(validation_x.len(), 2);
+            assert_eq!(train_x.len(), 8);
+            // Targets stay paired with their features.
+            for (x, y) in train_x
+                .iter()
+                .zip(&train_y)
+                .chain(validation_x.iter().zip(&validation_y))
+            {
+                assert_eq!(*y, x * 10);
+            }
+            assert!(validation_x.iter().all(|x| !train_x.contains(x)));
+            for x in validation_x {
+                assert!(seen.insert(x), "{} is in two validation sets", x);
+            }
+        }
+        assert_eq!(folds, 5);
+        assert_eq!(seen.len(), 10);
+    }
+
+    #[test]
+    fn train_test_split_partitions_the_data() {
+        let features: Vec<usize> = (0..10).collect();
+        let targets: Vec<usize> = (0..10).map(|x| x * 10).collect();
+        // A fraction of 0.0 or 1.0 leaves one side empty.
+        for (test_fraction, test_size) in [(0.3, 3), (0.0, 0), (1.0, 10)] {
+            let (train_x, train_y, test_x, test_y) =
+                train_test_split(&features, &targets, test_fraction, 7);
+            let train_size = 10 - test_size;
+            assert_eq!((train_x.len(), train_y.len()), (train_size, train_size));
+            assert_eq!((test_x.len(), test_y.len()), (test_size, test_size));
+            assert!(test_x.iter().all(|x| !train_x.contains(x)));
+            assert!(train_x.iter().zip(&train_y).all(|(x, y)| *y == x * 10));
+            assert!(test_x.iter().zip(&test_y).all(|(x, y)| *y == x * 10));
+
+            let all: HashSet<usize> = train_x.into_iter().chain(test_x).collect();
+            assert_eq!(all.len(), 10);
+        }
+    }
+
+    #[test]
+    fn one_hot_and_argmax() {
+        for index in [0, 2, 3] {
+            let encoded = one_hot(index, 4);
+            let data: Vec<f32> = encoded.iter().map(|v| v.data()).collect();
+            let mut expected = vec![0.0; 4];
+            expected[index] = 1.0;
+            assert_eq!(data, expected);
+            assert_eq!(argmax(&encoded), index);
+        }
+        let tied = [constant(1.0), constant(3.0), constant(3.0)];
+        assert_eq!(argmax(&tied), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "index 4 is out of range for 4 classes")]
+    fn one_hot_rejects_out_of_range_indices() {
+        one_hot(4, 4);
+    }
+}
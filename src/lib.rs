@@ -0,0 +1,6 @@
+pub mod data;
+pub mod loss;
+pub mod neural_net;
+pub mod optimizer;
+pub mod scheduler;
+pub mod value;
@@ -0,0 +1,305 @@
+// Loss functions for comparing predicted `Value`s against expected ones.
+
+use crate::value::{constant, Value};
+use std::iter::zip;
+
+// Controls how the per-element losses are aggregated.
+pub enum Reduction {
+    // The average of the per-element losses.
+    Mean,
+    // The sum of the per-element losses.
+    Sum,
+    // No aggregation, each per-element loss is returned as is.
+    None,
+}
+
+// Aggregate the per-element `losses` according to `reduction`. `Mean` and
+// `Sum` produce a single element, `None` returns `losses` unchanged. The sum
+// of no losses is 0.
+fn reduce(losses: Vec<Value>, reduction: Reduction) -> Vec<Value> {
+    match reduction {
+        Reduction::None => losses,
+        Reduction::Sum => vec![Value::sum_of(&losses)],
+        Reduction::Mean => vec![mean_of(&losses)],
+    }
+}
+
+// The mean of the losses, computed by dividing their sum by the count so
+// that each loss receives a gradient of exactly 1 / n. There must be at
+// least one loss, since the mean of none is undefined.
+pub fn mean_of(losses: &[Value]) -> Value {
+    assert!(!losses.is_empty(), "can't take the mean of no losses");
+    let sum = reduce(losses.to_vec(), Reduction::Sum).remove(0);
+    sum.div(&constant(losses.len() as f32))
+}
+
+// Scale each per-example loss by its weight, if any weights are given.
+fn weigh(losses: Vec<Value>, weights: Option<&[f32]>) -> Vec<Value> {
+    match weights {
+        None => losses,
+        Some(weights) => {
+            assert_eq!(
+                weights.len(),
+                losses.len(),
+                "expected one weight per example"
+            );
+            zip(losses, weights)
+                .map(|(loss, weight)| loss.mul(&constant(*weight)))
+                .collect()
+        }
+    }
+}
+
+// The squared error between each predicted and actual example. Each
+// example can have several outputs, in which case its loss is the sum of
+// the squared errors across all of its outputs. When `weights` are given,
+// each example's loss is scaled by its weight before the reduction.
+pub fn mse(
+    predicted: &[Vec<Value>],
+    actual: &[Vec<Value>],
+    weights: Option<&[f32]>,
+    reduction: Reduction,
+) -> Vec<Value> {
+    assert_eq!(
+        predicted.len(),
+        actual.len(),
+        "expected one target per prediction"
+    );
+    let squared_errors = zip(predicted, actual)
+        .map(|(p, a)| Mse.compute(p, a))
+        .collect();
+    reduce(weigh(squared_errors, weights), reduction)
+}
+
+// The log of the softmax of `logits`, i.e. x_i - ln(sum_j e^x_j). This uses
+// the log-sum-exp trick: the largest logit is subtracted before
+// exponentiating, so e^x can't overflow even for very large logits. The
+// shift is a constant, so it doesn't change the gradients.
+pub fn log_softmax(logits: &[Value]) -> Vec<Value> {
+    let max = constant(
+        logits
+            .iter()
+            .map(|x| x.data())
+            .fold(f32::NEG_INFINITY, f32::max),
+    );
+    let exps: Vec<Value> = logits.iter().map(|x| x.subtract(&max).exp()).collect();
+    let log_sum_exp = reduce(exps, Reduction::Sum).remove(0).ln().add(&max);
+    logits.iter().map(|x| x.subtract(&log_sum_exp)).collect()
+}
+
+// The negative log likelihood of the class at `target`, given the log
+// probabilities of each class (e.g. from `log_softmax`).
+pub fn nll_loss(log_probs: &[Value], target: usize) -> Value {
+    log_probs[target].mul(&constant(-1.0))
+}
+
+// A loss between one predicted example and its target, so training can be
+// run with any loss function.
+pub trait Loss {
+    // The loss of a single example.
+    fn compute(&self, predicted: &[Value], target: &[Value]) -> Value;
+
+    // The mean of the per-example losses over a batch. The divisor is the
+    // number of examples actually given, so a smaller final batch is still
+    // averaged correctly.
+    fn batch(&self, predicted: &[Vec<Value>], targets: &[Vec<Value>]) -> Value {
+        assert_eq!(
+            predicted.len(),
+            targets.len(),
+            "expected one target per prediction"
+        );
+        let losses: Vec<Value> = zip(predicted, targets)
+            .map(|(p, t)| self.compute(p, t))
+            .collect();
+        mean_of(&losses)
+    }
+}
+
+// The sum of the squared errors across the outputs, as in `mse`.
+pub struct Mse;
+
+impl Loss for Mse {
+    fn compute(&self, predicted: &[Value], target: &[Value]) -> Value {
+        assert_eq!(
+            predicted.len(),
+            target.len(),
+            "expected one target per output"
+        );
+        let errors = zip(predicted, target).map(|(p, t)| p.subtract(t).squared());
+        reduce(errors.collect(), Reduction::Sum).remove(0)
+    }
+}
+
+// The sum of the absolute errors across the outputs. Less sensitive to
+// outliers than `Mse`.
+pub struct Mae;
+
+impl Loss for Mae {
+    fn compute(&self, predicted: &[Value], target: &[Value]) -> Value {
+        assert_eq!(
+            predicted.len(),
+            target.len(),
+            "expected one target per output"
+        );
+        let errors = zip(predicted, target).map(|(p, t)| p.subtract(t).abs());
+        reduce(errors.collect(), Reduction::Sum).remove(0)
+    }
+}
+
+// The cross entropy between the softmax of the predicted logits and the
+// target class probabilities, e.g. a `one_hot` vector.
+pub struct CrossEntropy;
+
+impl Loss for CrossEntropy {
+    fn compute(&self, predicted: &[Value], target: &[Value]) -> Value {
+        assert_eq!(
+            predicted.len(),
+            target.len(),
+            "expected one target per output"
+        );
+        let terms = zip(log_softmax(predicted), target).map(|(p, t)| p.mul(t));
+        reduce(terms.collect(), Reduction::Sum)
+            .remove(0)
+            .mul(&constant(-1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural_net::NeuralNet;
+    use crate::value::param;
+
+    fn examples() -> (Vec<Vec<Value>>, Vec<Vec<Value>>) {
+        let predicted = vec![vec![param(1.0), param(2.0)], vec![param(-1.0), param(0.5)]];
+        let actual = vec![
+            vec![constant(0.0), constant(4.0)],
+            vec![constant(1.0), constant(0.5)],
+        ];
+        (predicted, actual)
+    }
+
+    #[test]
+    fn sum_is_mean_times_count() {
+        let (predicted, actual) = examples();
+        let sum = mse(&predicted, &actual, None, Reduction::Sum).remove(0);
+        let mean = mse(&predicted, &actual, None, Reduction::Mean).remove(0);
+        assert_eq!(sum.data(), 9.0);
+        assert_eq!(sum.data(), mean.data() * 2.0);
+    }
+
+    #[test]
+    fn no_reduction_returns_each_loss() {
+        let (predicted, actual) = examples();
+        let losses = mse(&predicted, &actual, None, Reduction::None);
+        assert_eq!(losses.len(), 2);
+        assert_eq!(losses[0].data(), 5.0);
+        assert_eq!(losses[1].data(), 4.0);
+        // d/dp (p - a)^2 = 2 * (p - a), and the second example's loss doesn't
+        // depend on the first example's predictions.
+        losses[0].compute_gradients();
+        assert_eq!(predicted[0][0].gradient(), 2.0);
+        assert_eq!(predicted[0][1].gradient(), -4.0);
+        assert_eq!(predicted[1][0].gradient(), 0.0);
+        losses[1].compute_gradients();
+        assert_eq!(predicted[1][0].gradient(), -4.0);
+        assert_eq!(predicted[1][1].gradient(), 0.0);
+    }
+
+    #[test]
+    fn empty_sums_are_zero() {
+        assert_eq!(Mse.compute(&[], &[]).data(), 0.0);
+        assert_eq!(mse(&[], &[], None, Reduction::Sum)[0].data(), 0.0);
+        assert_eq!(
+            mse(&[vec![]], &[vec![]], None, Reduction::Mean)[0].data(),
+            0.0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can't take the mean of no losses")]
+    fn mean_of_no_losses_panics() {
+        Mse.batch(&[], &[]);
+    }
+
+    #[test]
+    fn mse_sums_errors_across_outputs() {
+        // Outputs (x + y, x - y).
+        let net = NeuralNet::from_weights(
+            vec![2, 2],
+            vec![vec![vec![1.0, 1.0], vec![1.0, -1.0]]],
+            vec![vec![0.0, 0.0]],
+        );
+        let predicted = vec![net.forward(vec![constant(3.0), constant(1.0)])];
+        let actual = vec![vec![constant(5.0), constant(2.0)]];
+        let loss = mse(&predicted, &actual, None, Reduction::Mean).remove(0);
+        // (4 - 5)^2 + (2 - 2)^2
+        assert_eq!(loss.data(), 1.0);
+        loss.compute_gradients();
+        let gradients: Vec<f32> = net.parameters().iter().map(|p| p.gradient()).collect();
+        // Only the first output is off, by -1, so its weights get 2 * -1 * input.
+        assert_eq!(gradients, vec![-6.0, -2.0, -2.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn weights_scale_each_examples_gradient() {
+        let gradient_with = |weights: Option<&[f32]>| {
+            let (predicted, actual) = examples();
+            mse(&predicted, &actual, weights, Reduction::Sum)[0].compute_gradients();
+            (predicted[0][0].gradient(), predicted[1][0].gradient())
+        };
+        let (first, second) = gradient_with(None);
+        let (weighted_first, weighted_second) = gradient_with(Some(&[2.0, 1.0]));
+        assert_eq!(weighted_first, 2.0 * first);
+        assert_eq!(weighted_second, second);
+    }
+
+    #[test]
+    fn mean_gives_each_loss_one_nth() {
+        let losses: Vec<Value> = [1.0, 2.0, 4.0].into_iter().map(param).collect();
+        let mean = mean_of(&losses);
+        mean.compute_gradients();
+        assert!((mean.data() - 7.0 / 3.0).abs() < 1e-6);
+        for loss in &losses {
+            assert!((loss.gradient() - 1.0 / 3.0).abs() < 1e-7);
+        }
+        // The same as multiplying the sum by 1 / n.
+        let scaled = Value::sum_of(&losses).mul(&constant(1.0 / 3.0));
+        assert!((scaled.data() - mean.data()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn log_softmax_is_stable_for_extreme_logits() {
+        let logits = [param(100.0), param(0.0), param(-100.0)];
+        let naive_sum: Vec<Value> = logits
+            .iter()
+            .map(|x| x.mul(&constant(10.0)).exp())
+            .collect();
+        assert!(!Value::sum_of(&naive_sum).ln().is_finite());
+
+        let scaled: Vec<Value> = logits.iter().map(|x| x.mul(&constant(10.0))).collect();
+        let loss = nll_loss(&log_softmax(&scaled), 1);
+        loss.compute_gradients();
+        assert!(loss.is_finite());
+        assert!((loss.data() - 1000.0).abs() < 1e-3);
+        // softmax - one_hot, times the scale of 10
+        let gradients: Vec<f32> = logits.iter().map(|x| x.gradient()).collect();
+        assert!((gradients[0] - 10.0).abs() < 1e-4);
+        assert!((gradients[1] + 10.0).abs() < 1e-4);
+        assert!(gradients[2].abs() < 1e-4);
+    }
+
+    #[test]
+    fn ragged_batches_divide_by_their_own_size() {
+        // Squared errors 1, 4, 9, 16, 25 split into batches of 2.
+        let predicted: Vec<Vec<Value>> = (1..=5).map(|i| vec![param(i as f32)]).collect();
+        let targets: Vec<Vec<Value>> = (0..5).map(|_| vec![constant(0.0)]).collect();
+        let losses: Vec<f32> = zip(predicted.chunks(2), targets.chunks(2))
+            .map(|(p, t)| Mse.batch(p, t).data())
+            .collect();
+        assert_eq!(losses, vec![2.5, 12.5, 25.0]);
+        let last = Mse.batch(&predicted[4..], &targets[4..]);
+        last.compute_gradients();
+        assert_eq!(predicted[4][0].gradient(), 10.0);
+    }
+}
@@ -1,43 +1,44 @@
-mod value;
-use value::value;
-
-mod neural_net;
-use neural_net::NeuralNet;
+use rustgrad::loss::Mse;
+use rustgrad::neural_net::{NeuralNet, TrainOptions};
+use rustgrad::optimizer::Sgd;
+use rustgrad::value::constant;
 
 fn main() {
     let net = NeuralNet::new(vec![2, 3, 7, 1]);
-    for _i in 0..1000 {
-        let inputs = vec![
-            vec![value(5.0), value(5.0)],
-            vec![value(4.0), value(3.0)],
-            vec![value(10.0), value(3.0)],
-            vec![value(-15.0), value(3.0)],
-            vec![value(-5.0), value(3.0)],
-        ];
-        let expected_outputs = vec![
-            value(10.0),
-            value(7.0),
-            value(13.0),
-            value(-12.0),
-            value(-2.0),
-        ];
-        let square_error = inputs
-            .iter()
-            .map(|input| net.forward(input.clone()))
-            .zip(expected_outputs)
-            .map(|(predicted, actual)| predicted[0].subtract(&actual).squared())
-            .reduce(|x, y| x.add(&y))
-            .unwrap();
-        let inverse_size = value(1.0 / (inputs.len() as f32));
-        let mut mean_square_error = square_error.mul(&inverse_size);
-        println!("loss={}", mean_square_error.data());
-        mean_square_error.compute_gradients();
-        net.parameters().iter().for_each(|v| v.learn(0.0001));
+    let inputs = [
+        vec![constant(5.0), constant(5.0)],
+        vec![constant(4.0), constant(3.0)],
+        vec![constant(10.0), constant(3.0)],
+        vec![constant(-15.0), constant(3.0)],
+        vec![constant(-5.0), constant(3.0)],
+    ];
+    let expected_outputs = [
+        vec![constant(10.0)],
+        vec![constant(7.0)],
+        vec![constant(13.0)],
+        vec![constant(-12.0)],
+        vec![constant(-2.0)],
+    ];
+    let mut optimizer = Sgd::new(net.parameters(), 0.0001);
+    let losses = net
+        .train_full_batch(
+            &inputs,
+            &expected_outputs,
+            1000,
+            &Mse,
+            &mut optimizer,
+            TrainOptions::default(),
+        )
+        .expect("training failed");
+    match losses.last() {
+        Some(loss) if !loss.is_finite() => eprintln!("training diverged, stopping"),
+        Some(loss) => eprintln!("final loss={}", loss),
+        None => (),
     }
 
     net.dump();
     println!(
         "9 + 4 = {}",
-        net.forward(vec![value(9.0), value(4.0)])[0].data()
+        net.forward(vec![constant(9.0), constant(4.0)])[0].data()
     );
 }
@@ -1,96 +1,628 @@
 // A basic neural network package which leverages the `Value` class
 // as it's base element.
 
-use crate::value::{value, Value};
-use rand::{self, Rng};
+use crate::loss::Loss;
+use crate::optimizer::Optimizer;
+use crate::value::{constant, param, Value};
+use rand::rngs::StdRng;
+use rand::{self, Rng, SeedableRng};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
 use std::iter::zip;
+use std::ops::Range;
 
-// A single neuron which multiplies each input feature
-// against each weight and adds the bias.
-struct Neuron {
-    weights: Vec<Value>,
-    bias: Value,
+// A non-linearity applied element-wise to the outputs of a layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    // The identity, i.e. no activation.
+    Linear,
+    Relu,
+    Relu6,
+    Sigmoid,
+    Tanh,
+    Silu,
 }
 
-impl Neuron {
-    fn new(parameters: usize) -> Neuron {
-        let mut rng = rand::thread_rng();
-        Neuron {
-            weights: (0..parameters)
-                .map(|_x| value(rng.gen_range(-1.0..1.0)))
-                .collect(),
-            bias: value(rng.gen_range(-1.0..1.0)),
+impl Activation {
+    pub fn apply(&self, v: &Value) -> Value {
+        match self {
+            Activation::Linear => v.clone(),
+            Activation::Relu => v.relu(),
+            Activation::Relu6 => v.relu6(),
+            Activation::Sigmoid => v.sigmoid(),
+            Activation::Tanh => v.tanh(),
+            Activation::Silu => v.silu(),
         }
     }
 
-    fn forward(&self, inputs: &Vec<Value>) -> Value {
-        zip(&self.weights, inputs)
-            .map(|(w, i)| w.mul(&i))
-            .reduce(|x, y| x.add(&y))
-            .unwrap()
-            .add(&self.bias)
-    }
-
-    fn parameters(&self) -> Vec<Value> {
-        self.weights
-            .clone()
-            .into_iter()
-            .chain([self.bias.clone()])
-            .collect()
+    // Same as `apply`, but on a raw float, so no operation graph is built.
+    pub fn apply_data(&self, x: f32) -> f32 {
+        match self {
+            Activation::Linear => x,
+            Activation::Relu => x.max(0.0),
+            Activation::Relu6 => x.clamp(0.0, 6.0),
+            Activation::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+            Activation::Tanh => x.tanh(),
+            Activation::Silu => x / (1.0 + (-x).exp()),
+        }
     }
 }
 
-// A layer of neurons.
+// A fully connected layer. The layer stores a weight matrix with one row
+// per output (i.e. per neuron) and one column per input, plus a bias per
+// output. The forward pass is a single matrix-vector product plus the bias
+// vector.
+//
+// When `constant_input` is set, a constant input feature of 1 is prepended
+// to the layer's inputs, mirroring the textbook formulation where the bias
+// is just the weight of an always-on input. The weight matrix then has one
+// extra column for that feature. The layer's output size is unaffected, so
+// the next layer doesn't need to know about the augmented input.
+//
+// Each neuron applies its own entry of `activations` to its output, which
+// lets a single layer mix activations. Layers are linear by default.
+//
+// When `residual` is set, the layer's input is added to its output (a skip
+// connection), which requires the input and output sizes to match. The skip
+// is added after the activations.
 struct Layer {
-    neurons: Vec<Neuron>,
+    weights: Vec<Vec<Value>>,
+    biases: Vec<Value>,
+    activations: Vec<Activation>,
+    constant_input: bool,
+    residual: bool,
+}
+
+// Which kind of parameter an initializer is asked for, so weights and
+// biases can be initialized differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParamKind {
+    Weight,
+    Bias,
+}
+
+// Returns the initial value of the next parameter of the given kind. The
+// rng is the one passed to `NeuralNet::reinit`, so seeded initializers are
+// reproducible.
+type InitFn = dyn FnMut(&mut StdRng, ParamKind) -> f32;
+
+// The initialization used by `NeuralNet::new`: weights are sampled uniformly
+// from (-1, 1) and biases start at 0.
+fn uniform_init(rng: &mut StdRng, kind: ParamKind) -> f32 {
+    match kind {
+        ParamKind::Weight => rng.gen_range(-1.0..1.0),
+        ParamKind::Bias => 0.0,
+    }
 }
 
 impl Layer {
-    fn new(input_size: usize, output_size: usize) -> Layer {
+    // Create a layer whose weights and biases are each initialized to the
+    // next value returned by `init` for their kind. Each row's weights are
+    // created before its bias.
+    fn new_with(
+        input_size: usize,
+        output_size: usize,
+        constant_input: bool,
+        mut init: impl FnMut(ParamKind) -> f32,
+    ) -> Layer {
+        let augmented_size = if constant_input {
+            input_size + 1
+        } else {
+            input_size
+        };
+        let mut weights = Vec::new();
+        let mut biases = Vec::new();
+        for _row in 0..output_size {
+            weights.push(
+                (0..augmented_size)
+                    .map(|_x| param(init(ParamKind::Weight)))
+                    .collect(),
+            );
+            biases.push(param(init(ParamKind::Bias)));
+        }
         Layer {
-            neurons: (0..output_size).map(|_x| Neuron::new(input_size)).collect(),
+            weights,
+            biases,
+            activations: vec![Activation::Linear; output_size],
+            constant_input,
+            residual: false,
         }
     }
 
-    fn forward(&self, inputs: &Vec<Value>) -> Vec<Value> {
-        self.neurons
-            .iter()
-            .map(|neuron| neuron.forward(inputs))
-            .collect()
+    // The weighted inputs of each neuron are summed with `sum_balanced`,
+    // which keeps the graph depth logarithmic in the input size. When
+    // `fused` is set, each neuron is instead a single `Value::linear`.
+    fn forward(&self, inputs: &[Value], fused: bool) -> Vec<Value> {
+        let augmented: Vec<Value> = if self.constant_input {
+            [constant(1.0)].iter().chain(inputs).cloned().collect()
+        } else {
+            inputs.to_vec()
+        };
+        let neurons = zip(zip(&self.weights, &self.biases), &self.activations);
+        let outputs = neurons.map(|((row, bias), activation)| {
+            let output = if fused {
+                Value::linear(row, &augmented, bias)
+            } else {
+                let products: Vec<Value> = zip(row, &augmented).map(|(w, i)| w.mul(i)).collect();
+                sum_balanced(&products).add(bias)
+            };
+            activation.apply(&output)
+        });
+        if self.residual {
+            zip(outputs, inputs).map(|(o, i)| o.add(i)).collect()
+        } else {
+            outputs.collect()
+        }
+    }
+
+    // Same as `forward` but operates on raw floats, so no operation
+    // graph is built.
+    fn forward_eval(&self, inputs: &[f32]) -> Vec<f32> {
+        let augmented: Vec<f32> = if self.constant_input {
+            [1.0].into_iter().chain(inputs.iter().copied()).collect()
+        } else {
+            inputs.to_vec()
+        };
+        let neurons = zip(zip(&self.weights, &self.biases), &self.activations);
+        let outputs = neurons.map(|((row, bias), activation)| {
+            let sum = zip(row, &augmented).map(|(w, i)| w.data() * i).sum::<f32>();
+            activation.apply_data(sum + bias.data())
+        });
+        if self.residual {
+            zip(outputs, inputs).map(|(o, i)| o + i).collect()
+        } else {
+            outputs.collect()
+        }
+    }
+
+    // The number of inputs the layer expects, not counting the constant input.
+    fn input_size(&self) -> usize {
+        let columns = self.weights[0].len();
+        if self.constant_input {
+            columns - 1
+        } else {
+            columns
+        }
     }
 
+    fn output_size(&self) -> usize {
+        self.weights.len()
+    }
+
+    // The name of the layer's activation for `NeuralNet::summary`: "none" for
+    // a linear layer and "mixed" when the neurons don't all share one.
+    fn activation_name(&self) -> String {
+        let first = self.activations[0];
+        if self.activations.iter().any(|a| *a != first) {
+            "mixed".to_string()
+        } else if first == Activation::Linear {
+            "none".to_string()
+        } else {
+            format!("{:?}", first).to_lowercase()
+        }
+    }
+
+    // Label each parameter "layer{l}.neuron{n}.weight{i}" or
+    // "layer{l}.neuron{n}.bias". With a constant input, weight0 is the
+    // weight of the constant feature.
+    fn name_parameters(&self, layer_index: usize) {
+        for (neuron, (row, bias)) in zip(&self.weights, &self.biases).enumerate() {
+            for (input, weight) in row.iter().enumerate() {
+                weight.set_label(&format!(
+                    "layer{}.neuron{}.weight{}",
+                    layer_index, neuron, input
+                ));
+            }
+            bias.set_label(&format!("layer{}.neuron{}.bias", layer_index, neuron));
+        }
+    }
+
+    // The parameters of each neuron in turn: its row of weights followed
+    // by its bias.
     fn parameters(&self) -> Vec<Value> {
-        self.neurons
-            .iter()
-            .flat_map(|neuron| neuron.parameters())
+        zip(&self.weights, &self.biases)
+            .flat_map(|(row, bias)| row.iter().chain([bias]).cloned())
             .collect()
     }
 }
 
+// See `NeuralNet::weight_stats`. `std` is the population standard deviation.
+#[derive(Debug)]
+pub struct WeightStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std: f32,
+}
+
+// The optional settings of `NeuralNet::train_full_batch`. Override only
+// what you need, e.g. `TrainOptions { verbose: 1, ..Default::default() }`.
+pub struct TrainOptions<'a> {
+    // Progress output: 0 is silent, 1 prints the loss of each epoch and 2 the
+    // loss of each batch. Every epoch is a single batch, so 2 currently
+    // prints the same as 1.
+    pub verbose: usize,
+    // Where the progress output goes. Defaults to stderr.
+    pub progress: Option<&'a mut dyn Write>,
+    // If set, the file there is overwritten with the loss history as CSV: an
+    // `epoch,loss` header and then one row per epoch.
+    pub log_path: Option<&'a str>,
+    // Stop training once the loss stops being finite, since it will never
+    // recover from that. The non-finite loss is still returned.
+    pub stop_on_non_finite: bool,
+}
+
+impl Default for TrainOptions<'_> {
+    fn default() -> Self {
+        TrainOptions {
+            verbose: 0,
+            progress: None,
+            log_path: None,
+            stop_on_non_finite: true,
+        }
+    }
+}
+
 // A collection of layers.
 pub struct NeuralNet {
     layers: Vec<Layer>,
+    // Each parameter's gradient the last time `grad_sign_flip_ratio` was
+    // called.
+    previous_gradients: RefCell<Vec<f32>>,
+    // The initialization the network was created with, so that `reinit`
+    // can repeat it.
+    init: RefCell<Box<InitFn>>,
 }
 
 impl NeuralNet {
+    // `layer_sizes` lists the input size followed by the output size of each
+    // layer, so it must have at least two entries, none of which may be zero.
+    // Weights are sampled uniformly from (-1, 1) and biases start at 0, as is
+    // common practice. Use `new_with` to initialize the biases as well.
     pub fn new(layer_sizes: Vec<usize>) -> NeuralNet {
+        NeuralNet::build(layer_sizes, false, Box::new(uniform_init))
+    }
+
+    // Same as `new`, but every weight and bias is initialized to the next
+    // value returned by `init`, e.g. `|| 0.5` for a constant initialization.
+    // `reinit` calls `init` again, without the seed.
+    pub fn new_with(layer_sizes: Vec<usize>, mut init: impl FnMut() -> f32 + 'static) -> NeuralNet {
+        NeuralNet::build(layer_sizes, false, Box::new(move |_rng, _kind| init()))
+    }
+
+    // Build a network with explicit parameters. `weights[l]` is the weight
+    // matrix of layer `l`, with one row per output and one column per input,
+    // and `biases[l]` holds one bias per output. The shapes must match
+    // `layer_sizes`.
+    pub fn from_weights(
+        layer_sizes: Vec<usize>,
+        weights: Vec<Vec<Vec<f32>>>,
+        biases: Vec<Vec<f32>>,
+    ) -> NeuralNet {
+        let layer_count = layer_sizes.len().saturating_sub(1);
+        assert_eq!(
+            weights.len(),
+            layer_count,
+            "expected one weight matrix per layer"
+        );
+        assert_eq!(
+            biases.len(),
+            layer_count,
+            "expected one bias vector per layer"
+        );
+        for layer_index in 0..layer_count {
+            let (input_size, output_size) =
+                (layer_sizes[layer_index], layer_sizes[layer_index + 1]);
+            assert_eq!(
+                weights[layer_index].len(),
+                output_size,
+                "layer {} weight matrix should have {} rows",
+                layer_index,
+                output_size
+            );
+            assert!(
+                weights[layer_index]
+                    .iter()
+                    .all(|row| row.len() == input_size),
+                "layer {} weight matrix should have {} columns",
+                layer_index,
+                input_size
+            );
+            assert_eq!(
+                biases[layer_index].len(),
+                output_size,
+                "layer {} should have {} biases",
+                layer_index,
+                output_size
+            );
+        }
+        // Feed the parameters to the initializer in the order `Layer` creates
+        // them: each row's weights followed by its bias. Once they run out,
+        // e.g. for `reinit`, the network is initialized like `new`.
+        let mut parameters = zip(&weights, &biases)
+            .flat_map(|(matrix, bias)| {
+                zip(matrix, bias).flat_map(|(row, b)| row.iter().chain([b]).copied())
+            })
+            .collect::<Vec<f32>>()
+            .into_iter();
+        NeuralNet::build(
+            layer_sizes,
+            false,
+            Box::new(move |rng, kind| parameters.next().unwrap_or_else(|| uniform_init(rng, kind))),
+        )
+    }
+
+    // Same as `new`, but every layer is given a constant input feature of 1
+    // in addition to the per-neuron bias. See `Layer` for details.
+    pub fn with_constant_inputs(layer_sizes: Vec<usize>) -> NeuralNet {
+        NeuralNet::build(layer_sizes, true, Box::new(uniform_init))
+    }
+
+    // Add a skip connection around the layer at index `layer`, so that its
+    // input is added to its output. The layer's input and output sizes must
+    // match.
+    pub fn with_residual(mut self, layer: usize) -> NeuralNet {
+        let target = &mut self.layers[layer];
+        assert_eq!(
+            target.input_size(),
+            target.output_size(),
+            "a residual layer needs matching input and output sizes"
+        );
+        target.residual = true;
+        self
+    }
+
+    // Give each neuron of the layer at index `layer` its own activation.
+    // `activations` must have one entry per neuron, i.e. per output.
+    pub fn with_activations(mut self, layer: usize, activations: Vec<Activation>) -> NeuralNet {
+        let target = &mut self.layers[layer];
+        assert_eq!(
+            activations.len(),
+            target.output_size(),
+            "expected one activation per neuron"
+        );
+        target.activations = activations;
+        self
+    }
+
+    fn build(layer_sizes: Vec<usize>, constant_input: bool, mut init: Box<InitFn>) -> NeuralNet {
+        // Checked separately since computing the number of layers below would
+        // otherwise underflow.
+        assert!(
+            !layer_sizes.is_empty(),
+            "layer_sizes is empty, it must contain the input size followed by each layer's output size"
+        );
+        assert!(
+            layer_sizes.len() >= 2,
+            "a network needs at least an input and an output size, got {:?}",
+            layer_sizes
+        );
+        assert!(
+            layer_sizes.iter().all(|size| *size > 0),
+            "layer sizes must be non-zero, got {:?}",
+            layer_sizes
+        );
+        let mut rng = StdRng::from_entropy();
+        let layers = (0..layer_sizes.len() - 1)
+            .map(|layer_index| {
+                let layer = Layer::new_with(
+                    layer_sizes[layer_index],
+                    layer_sizes[layer_index + 1],
+                    constant_input,
+                    |kind| init(&mut rng, kind),
+                );
+                layer.name_parameters(layer_index);
+                layer
+            })
+            .collect();
         NeuralNet {
-            layers: (0..layer_sizes.len() - 1)
-                .map(|layer_index| {
-                    Layer::new(layer_sizes[layer_index], layer_sizes[layer_index + 1])
-                })
-                .collect(),
+            layers,
+            previous_gradients: RefCell::new(Vec::new()),
+            init: RefCell::new(init),
         }
     }
 
     pub fn forward(&self, inputs: Vec<Value>) -> Vec<Value> {
         let mut output = inputs;
         for layer in &self.layers {
-            output = layer.forward(&output);
+            output = layer.forward(&output, false);
+        }
+        output
+    }
+
+    // Same as `forward`, but each neuron is a single fused `Value::linear`
+    // operation rather than a multiplication per weight and a tree of
+    // additions. The outputs and gradients are the same, while the graph is
+    // much smaller and quicker to build.
+    pub fn forward_fused(&self, inputs: Vec<Value>) -> Vec<Value> {
+        let mut output = inputs;
+        for layer in &self.layers {
+            output = layer.forward(&output, true);
+        }
+        output
+    }
+
+    // Run the forward pass and return the outputs as fresh constants that
+    // are disconnected from this network, e.g. for the targets of a target
+    // network. Gradients computed from the snapshot never reach the
+    // network's parameters.
+    pub fn snapshot_forward(&self, inputs: Vec<Value>) -> Vec<Value> {
+        self.forward(inputs)
+            .iter()
+            .map(|output| constant(output.data()))
+            .collect()
+    }
+
+    // Inference-only forward pass. Computes the same outputs as `forward`
+    // using plain float arithmetic on the learned weights, without
+    // allocating any `Value` objects or building an operation graph.
+    pub fn forward_eval(&self, inputs: &[f32]) -> Vec<f32> {
+        let mut output = inputs.to_vec();
+        for layer in &self.layers {
+            output = layer.forward_eval(&output);
         }
         output
     }
 
+    // Run the forward pass over every example, compute the mean `loss` of
+    // the predictions against `targets` (see `Loss::batch`), and run the
+    // backward pass from it. Returns the value of the loss, leaving the
+    // gradients on the parameters ready for an update step.
+    //
+    // The backward pass is `Value::compute_param_gradients`, so it skips the
+    // parts of the graph that only depend on inputs and frozen parameters
+    // (see `freeze_matching`). Those values, including the frozen parameters,
+    // are left with a gradient of 0.
+    pub fn loss_and_backward(
+        &self,
+        inputs: &[Vec<Value>],
+        targets: &[Vec<Value>],
+        loss: &impl Loss,
+    ) -> f32 {
+        let predicted: Vec<Vec<Value>> = inputs
+            .iter()
+            .map(|input| self.forward(input.clone()))
+            .collect();
+        let loss = loss.batch(&predicted, targets);
+        loss.compute_param_gradients();
+        loss.data()
+    }
+
+    // Full-batch gradient descent: for each of `epochs` epochs, compute the
+    // mean `loss` over every example (e.g. `&Mse`), run the backward pass
+    // and step `optimizer` to update the parameters. Returns the loss of
+    // each epoch, or the first error from writing progress or the loss log.
+    // See `TrainOptions` for the optional settings.
+    pub fn train_full_batch(
+        &self,
+        inputs: &[Vec<Value>],
+        targets: &[Vec<Value>],
+        epochs: usize,
+        loss: &impl Loss,
+        optimizer: &mut impl Optimizer,
+        options: TrainOptions,
+    ) -> io::Result<Vec<f32>> {
+        let mut stderr = io::stderr();
+        let progress = options.progress.unwrap_or(&mut stderr);
+        let mut csv = match options.log_path {
+            Some(path) => {
+                let mut file = File::create(path)?;
+                writeln!(file, "epoch,loss")?;
+                Some(file)
+            }
+            None => None,
+        };
+        let mut losses = Vec::new();
+        for epoch in 0..epochs {
+            let loss = self.loss_and_backward(inputs, targets, loss);
+            if options.verbose >= 1 {
+                writeln!(progress, "epoch {}: loss={}", epoch, loss)?;
+            }
+            if let Some(file) = &mut csv {
+                writeln!(file, "{},{}", epoch, loss)?;
+            }
+            losses.push(loss);
+            if options.stop_on_non_finite && !loss.is_finite() {
+                break;
+            }
+            optimizer.step();
+        }
+        Ok(losses)
+    }
+
+    // A single online learning step on one example: forward, `loss`,
+    // backward and an `optimizer` step. The graph for the
+    // example is dropped afterwards. Returns the loss before the update.
+    pub fn train_one(
+        &self,
+        input: &[f32],
+        target: &[f32],
+        loss: &impl Loss,
+        optimizer: &mut impl Optimizer,
+    ) -> f32 {
+        let inputs = [input.iter().map(|x| constant(*x)).collect()];
+        let targets = [target.iter().map(|y| constant(*y)).collect()];
+        let loss = self.loss_and_backward(&inputs, &targets, loss);
+        optimizer.step();
+        loss
+    }
+
+    // The average of `loss` over each example, without running the backward
+    // pass. Examples are processed one at a time and each example's graph is
+    // dropped as soon as its loss has been read, so memory use doesn't grow
+    // with the size of the dataset. This matches `loss.batch` over the whole
+    // dataset. The dataset must not be empty.
+    pub fn evaluate(&self, inputs: &[Vec<Value>], targets: &[Vec<Value>], loss: &impl Loss) -> f32 {
+        assert_eq!(
+            inputs.len(),
+            targets.len(),
+            "expected one target per example"
+        );
+        assert!(!inputs.is_empty(), "can't evaluate on an empty dataset");
+        let total: f32 = zip(inputs, targets)
+            .map(|(input, target)| {
+                let predicted = self.forward(input.clone());
+                loss.compute(&predicted, target).data()
+            })
+            .sum();
+        total / inputs.len() as f32
+    }
+
+    // The layer sizes this network was built from: the input size followed by
+    // the output size of each layer, as passed to `new`.
+    pub fn architecture(&self) -> Vec<usize> {
+        [self.layers[0].input_size()]
+            .into_iter()
+            .chain(self.layers.iter().map(|layer| layer.output_size()))
+            .collect()
+    }
+
+    // An estimate of the number of multiply-adds in one forward pass: one
+    // per weight, e.g. 2 * 3 + 3 * 1 = 9 for a [2, 3, 1] network. Bias
+    // additions, activations and skip connections are not counted.
+    pub fn flops(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.output_size() * layer.weights[0].len())
+            .sum()
+    }
+
+    // Copy the current weights into a `CompiledNet` for fast repeated
+    // inference. The copy is independent, so later training of this network
+    // doesn't affect it.
+    pub fn compile(&self) -> CompiledNet {
+        CompiledNet {
+            layers: (0..self.layers.len())
+                .map(|index| {
+                    let (weights, biases) = self.layer_weights(index);
+                    CompiledLayer {
+                        weights,
+                        biases,
+                        activations: self.layers[index].activations.clone(),
+                        constant_input: self.layers[index].constant_input,
+                        residual: self.layers[index].residual,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // The weight matrix (one row per output) and bias vector of the layer at
+    // index `layer`, as plain floats. If the layer has a constant input, its
+    // weight is the first column of the matrix.
+    pub fn layer_weights(&self, layer: usize) -> (Vec<Vec<f32>>, Vec<f32>) {
+        let layer = &self.layers[layer];
+        (
+            layer
+                .weights
+                .iter()
+                .map(|row| row.iter().map(|w| w.data()).collect())
+                .collect(),
+            layer.biases.iter().map(|b| b.data()).collect(),
+        )
+    }
+
     pub fn parameters(&self) -> Vec<Value> {
         self.layers
             .iter()
@@ -98,17 +630,1418 @@ impl NeuralNet {
             .collect()
     }
 
+    // Same as `parameters`, but paired with each parameter's name, e.g.
+    // "layer0.neuron2.weight1" for the weight of input 1 of neuron 2 in the
+    // first layer. The names are the parameters' labels, so they also show
+    // up in `Value::dump` and `Value::to_dot`.
+    pub fn named_parameters(&self) -> Vec<(String, Value)> {
+        self.parameters()
+            .into_iter()
+            .map(|p| (p.label().unwrap_or_default(), p))
+            .collect()
+    }
+
+    // Freeze every parameter whose name (see `named_parameters`) contains
+    // `pattern`, e.g. "layer0" for the first layer, so that `learn` and the
+    // optimizers no longer update it. Frozen parameters still receive
+    // gradients from `Value::compute_gradients`, but the training methods
+    // skip them (see `loss_and_backward`).
+    pub fn freeze_matching(&self, pattern: &str) {
+        self.set_trainable_matching(pattern, false);
+    }
+
+    // Undo `freeze_matching` for every parameter whose name contains
+    // `pattern`.
+    pub fn unfreeze_matching(&self, pattern: &str) {
+        self.set_trainable_matching(pattern, true);
+    }
+
+    fn set_trainable_matching(&self, pattern: &str, trainable: bool) {
+        for (name, p) in self.named_parameters() {
+            if name.contains(pattern) {
+                p.set_trainable(trainable);
+            }
+        }
+    }
+
+    // The global L2 norm of the gradients of all parameters. Only meaningful
+    // after `compute_gradients` has been run on a loss built from this network.
+    pub fn grad_norm(&self) -> f32 {
+        self.parameters()
+            .iter()
+            .map(|p| p.gradient().powi(2))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    // The gradient of each input after a backward pass from a loss computed
+    // on this network's output for `inputs`, e.g. for building adversarial
+    // perturbations. The inputs are ordinary leaves, so this just reads
+    // their gradients.
+    pub fn input_gradients(&self, inputs: &[Value]) -> Vec<f32> {
+        inputs.iter().map(|input| input.gradient()).collect()
+    }
+
+    // Reset the gradient of every parameter to 0. Needed between update
+    // steps when accumulating gradients with `compute_gradients_with`.
+    pub fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.set_gradient(0.0);
+        }
+    }
+
+    // Summary statistics over every weight in the network (biases excluded),
+    // for spotting dead or exploding layers.
+    pub fn weight_stats(&self) -> WeightStats {
+        let weights: Vec<f32> = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.weights.iter().flatten().map(|w| w.data()))
+            .collect();
+        let count = weights.len() as f32;
+        let mean = weights.iter().sum::<f32>() / count;
+        let variance = weights.iter().map(|w| (w - mean).powi(2)).sum::<f32>() / count;
+        WeightStats {
+            min: weights.iter().copied().fold(f32::INFINITY, f32::min),
+            max: weights.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            std: variance.sqrt(),
+        }
+    }
+
+    // Clamp the gradient of every parameter into [-max_abs, max_abs].
+    pub fn clip_grad_value(&self, max_abs: f32) {
+        for p in self.parameters() {
+            p.set_gradient(p.gradient().clamp(-max_abs, max_abs));
+        }
+    }
+
+    // The fraction of parameters whose gradient changed sign since the last
+    // call, for spotting oscillating training. Call it once per step after
+    // the backward pass. The first call has nothing to compare against and
+    // returns 0. Gradients that are exactly 0 never count as a flip.
+    pub fn grad_sign_flip_ratio(&self) -> f32 {
+        let gradients: Vec<f32> = self.parameters().iter().map(|p| p.gradient()).collect();
+        let previous = self.previous_gradients.replace(gradients.clone());
+        if previous.len() != gradients.len() {
+            return 0.0;
+        }
+        let flips = zip(&previous, &gradients)
+            .filter(|(a, b)| *a * *b < 0.0)
+            .count();
+        flips as f32 / gradients.len() as f32
+    }
+
+    // The L2 norm of all weights and biases, useful for monitoring weight growth.
+    pub fn param_norm(&self) -> f32 {
+        self.parameters()
+            .iter()
+            .map(|p| p.data().powi(2))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    // Move each parameter's data a fraction `tau` of the way towards the
+    // matching parameter of `source`, e.g. for soft target network updates.
+    // A `tau` of 1 copies `source` exactly. Both networks must have the same
+    // architecture.
+    pub fn soft_update(&self, source: &NeuralNet, tau: f32) {
+        let (parameters, source_parameters) = (self.parameters(), source.parameters());
+        assert!(
+            source.architecture() == self.architecture()
+                && source_parameters.len() == parameters.len(),
+            "networks must have the same architecture"
+        );
+        for (p, s) in zip(parameters, source_parameters) {
+            p.set_data(p.lerp(&s, tau).data());
+        }
+    }
+
+    // A new network whose parameters are the element-wise mean of this
+    // network's and those of `others`, e.g. for model averaging. All of the
+    // networks must have the same architecture. The new network copies this
+    // network's activations and skip connections.
+    pub fn average_with(&self, others: &[&NeuralNet]) -> NeuralNet {
+        let parameters = self.parameters();
+        for other in others {
+            assert!(
+                other.architecture() == self.architecture()
+                    && other.parameters().len() == parameters.len(),
+                "networks must have the same architecture"
+            );
+        }
+        let others: Vec<Vec<Value>> = others.iter().map(|other| other.parameters()).collect();
+        let count = (others.len() + 1) as f32;
+        // As in `from_weights`, `reinit` initializes the new network like
+        // `new` once the means run out.
+        let mut means = parameters
+            .iter()
+            .enumerate()
+            .map(|(index, p)| {
+                let sum: f32 = others.iter().map(|o| o[index].data()).sum();
+                (p.data() + sum) / count
+            })
+            .collect::<Vec<f32>>()
+            .into_iter();
+        let mut net = NeuralNet::build(
+            self.architecture(),
+            self.layers[0].constant_input,
+            Box::new(move |rng, kind| means.next().unwrap_or_else(|| uniform_init(rng, kind))),
+        );
+        for (layer, source) in zip(&mut net.layers, &self.layers) {
+            layer.activations = source.activations.clone();
+            layer.residual = source.residual;
+        }
+        net
+    }
+
+    // Re-initialize all weights and biases in place, with the same
+    // initialization the network was created with, e.g. `new`'s or the one
+    // given to `new_with`. Passing a seed makes the result reproducible: two
+    // networks with the same architecture and initialization reinitialized
+    // with the same seed end up with identical parameters.
+    pub fn reinit(&self, seed: Option<u64>) {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut init = self.init.borrow_mut();
+        for layer in &self.layers {
+            for (row, bias) in zip(&layer.weights, &layer.biases) {
+                for w in row {
+                    w.set_data(init(&mut rng, ParamKind::Weight));
+                }
+                bias.set_data(init(&mut rng, ParamKind::Bias));
+            }
+        }
+    }
+
+    // Perform a single gradient descent step without using the operation
+    // graph. The gradient of `loss` w.r.t. each parameter is estimated by
+    // central finite differences: (loss(p + epsilon) - loss(p - epsilon)) / (2 * epsilon).
+    // This is much slower than `compute_gradients` but is a useful correctness
+    // baseline for the autodiff path. Like `learn` and the optimizers, it
+    // leaves frozen parameters (see `freeze_matching`) alone.
+    pub fn train_numerical(
+        &self,
+        loss: impl Fn(&NeuralNet) -> f32,
+        learning_rate: f32,
+        epsilon: f32,
+    ) {
+        let parameters: Vec<Value> = self
+            .parameters()
+            .into_iter()
+            .filter(|p| p.is_param())
+            .collect();
+        let gradients: Vec<f32> = parameters
+            .iter()
+            .map(|p| {
+                let original = p.data();
+                p.set_data(original + epsilon);
+                let loss_plus = loss(self);
+                p.set_data(original - epsilon);
+                let loss_minus = loss(self);
+                p.set_data(original);
+                (loss_plus - loss_minus) / (2.0 * epsilon)
+            })
+            .collect();
+        for (p, g) in zip(&parameters, gradients) {
+            p.set_data(p.data() - g * learning_rate);
+        }
+    }
+
+    // A table describing each layer's input and output sizes, activation and
+    // parameter count, followed by the total number of parameters.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{:<8}{:>8}{:>8}{:>12}{:>10}\n",
+            "layer", "input", "output", "activation", "params"
+        );
+        for (index, layer) in self.layers.iter().enumerate() {
+            summary += &format!(
+                "{:<8}{:>8}{:>8}{:>12}{:>10}\n",
+                index,
+                layer.input_size(),
+                layer.output_size(),
+                layer.activation_name(),
+                layer.parameters().len()
+            );
+        }
+        summary += &format!("total params: {}\n", self.parameters().len());
+        summary
+    }
+
     pub fn dump(&self) {
         for layer in &self.layers {
             println!("layer");
-            for neuron in &layer.neurons {
-                let ws: Vec<f32> = neuron
-                    .weights
-                    .clone()
-                    .into_iter()
-                    .map(|w| w.data())
-                    .collect();
-                println!("w={:?}, b={}", ws, neuron.bias.data());
+            for (row, bias) in zip(&layer.weights, &layer.biases) {
+                let ws: Vec<f32> = row.iter().map(|w| w.data()).collect();
+                println!("w={:?}, b={}", ws, bias.data());
+            }
+        }
+    }
+}
+
+// The index ranges covered by sliding a `window` over `length` elements
+// in steps of `stride`. If the windows don't evenly divide the input, the
+// last window is truncated at the end of the input.
+fn windows(length: usize, window: usize, stride: usize) -> Vec<Range<usize>> {
+    assert!(window > 0, "window must be non-zero");
+    assert!(stride > 0, "stride must be non-zero");
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let end = (start + window).min(length);
+        ranges.push(start..end);
+        if end == length {
+            break;
+        }
+        start += stride;
+    }
+    ranges
+}
+
+// Take the maximum over each window of the input. The gradient of each
+// output is routed only to the element that won its window.
+pub fn max_pool1d(input: &[Value], window: usize, stride: usize) -> Vec<Value> {
+    windows(input.len(), window, stride)
+        .into_iter()
+        .map(|range| {
+            input[range]
+                .iter()
+                .cloned()
+                .reduce(|x, y| x.max(&y))
+                .unwrap()
+        })
+        .collect()
+}
+
+// Take the mean over each window of the input. Each element receives an
+// equal share of its window's gradient, i.e. 1 / window size.
+pub fn avg_pool1d(input: &[Value], window: usize, stride: usize) -> Vec<Value> {
+    windows(input.len(), window, stride)
+        .into_iter()
+        .map(|range| {
+            let inverse_size = constant(1.0 / (range.len() as f32));
+            input[range]
+                .iter()
+                .cloned()
+                .reduce(|x, y| x.add(&y))
+                .unwrap()
+                .mul(&inverse_size)
+        })
+        .collect()
+}
+
+// Squash each output into its `(lo, hi)` bound using `lo + (hi - lo) * sigmoid(x)`,
+// a differentiable transform for regression targets that live in a known range.
+pub fn bounded(outputs: &[Value], bounds: &[(f32, f32)]) -> Vec<Value> {
+    assert_eq!(outputs.len(), bounds.len(), "expected one bound per output");
+    zip(outputs, bounds)
+        .map(|(output, (lo, hi))| output.sigmoid().mul(&constant(hi - lo)).add(&constant(*lo)))
+        .collect()
+}
+
+// Sum the values by recursively adding the sums of each half (pairwise
+// summation). Compared to a left-leaning `reduce` this accumulates less
+// floating point error and produces a graph of depth O(log n) instead of O(n).
+pub fn sum_balanced(values: &[Value]) -> Value {
+    match values.len() {
+        0 => constant(0.0),
+        1 => values[0].clone(),
+        n => sum_balanced(&values[..n / 2]).add(&sum_balanced(&values[n / 2..])),
+    }
+}
+
+// Add `bias` to every row of `batch`. The same bias values are shared by
+// every row, so their gradients accumulate the sum over the batch.
+pub fn broadcast_add(batch: &[Vec<Value>], bias: &[Value]) -> Vec<Vec<Value>> {
+    batch
+        .iter()
+        .map(|row| {
+            assert_eq!(row.len(), bias.len(), "expected one bias per column");
+            zip(row, bias).map(|(x, b)| x.add(b)).collect()
+        })
+        .collect()
+}
+
+// Sum the values, computing the forward value with Kahan compensated
+// summation, which tracks the rounding error lost by each addition and feeds
+// it back into the next one. Only the resulting data is affected; the result
+// is a new `Value::sum_of` node, so every value gets the same gradient as in
+// any other sum.
+//
+// The compensation only applies to the data computed here: if `recompute`
+// re-evaluates the sum after one of the values changes, it uses plain
+// summation.
+pub fn sum_kahan(values: &[Value]) -> Value {
+    let mut sum: f32 = 0.0;
+    let mut compensation: f32 = 0.0;
+    for v in values {
+        let y = v.data() - compensation;
+        let t = sum + y;
+        compensation = (t - sum) - y;
+        sum = t;
+    }
+    Value::sum_with_data(values, sum)
+}
+
+// A snapshot of a `NeuralNet`'s weights stored as plain floats, with no
+// `Value` or `Rc` overhead. See `NeuralNet::compile`.
+pub struct CompiledNet {
+    layers: Vec<CompiledLayer>,
+}
+
+struct CompiledLayer {
+    weights: Vec<Vec<f32>>,
+    biases: Vec<f32>,
+    activations: Vec<Activation>,
+    constant_input: bool,
+    residual: bool,
+}
+
+impl CompiledNet {
+    // Computes the same outputs as `NeuralNet::forward_eval`.
+    pub fn predict(&self, inputs: &[f32]) -> Vec<f32> {
+        let mut output = inputs.to_vec();
+        for layer in &self.layers {
+            let mut augmented = output.clone();
+            if layer.constant_input {
+                augmented.insert(0, 1.0);
+            }
+            let neurons = zip(zip(&layer.weights, &layer.biases), &layer.activations);
+            let outputs = neurons.map(|((row, bias), activation)| {
+                activation.apply_data(zip(row, &augmented).map(|(w, i)| w * i).sum::<f32>() + bias)
+            });
+            output = if layer.residual {
+                zip(outputs, &output).map(|(o, i)| o + i).collect()
+            } else {
+                outputs.collect()
+            };
+        }
+        output
+    }
+}
+
+// Apply `activation` to each of the values.
+pub fn activate(values: &[Value], activation: Activation) -> Vec<Value> {
+    values.iter().map(|v| activation.apply(v)).collect()
+}
+
+// The sum of the squares of the values, built as a balanced tree like
+// `sum_balanced`. Each value receives a gradient of 2 * value.
+pub fn sum_squared(values: &[Value]) -> Value {
+    let squares: Vec<Value> = values.iter().map(|v| v.squared()).collect();
+    sum_balanced(&squares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loss::Mae;
+    use crate::loss::Mse;
+    use crate::optimizer::Adam;
+    use crate::optimizer::Sgd;
+
+    #[test]
+    fn forward_eval_matches_forward() {
+        let net = NeuralNet::new(vec![3, 3, 4, 2])
+            .with_residual(0)
+            .with_activations(
+                1,
+                vec![
+                    Activation::Relu,
+                    Activation::Tanh,
+                    Activation::Silu,
+                    Activation::Sigmoid,
+                ],
+            );
+        let constant_net = NeuralNet::with_constant_inputs(vec![3, 4, 2]);
+        let inputs = [0.5, -1.5, 2.0];
+        for net in [net, constant_net] {
+            let graph: Vec<f32> = net
+                .forward(inputs.iter().map(|x| constant(*x)).collect())
+                .iter()
+                .map(|v| v.data())
+                .collect();
+            let eval = net.forward_eval(&inputs);
+            assert_eq!(graph.len(), eval.len());
+            for (g, e) in zip(graph, eval) {
+                assert!((g - e).abs() < 1e-5, "{} != {}", g, e);
+            }
+        }
+    }
+
+    fn parameter_data(net: &NeuralNet) -> Vec<f32> {
+        net.parameters().iter().map(|p| p.data()).collect()
+    }
+
+    // The examples from main.rs.
+    fn main_dataset() -> (Vec<Vec<Value>>, Vec<Vec<Value>>) {
+        let pairs = [
+            (5.0, 5.0),
+            (4.0, 3.0),
+            (10.0, 3.0),
+            (-15.0, 3.0),
+            (-5.0, 3.0),
+        ];
+        let inputs = pairs
+            .iter()
+            .map(|(a, b)| vec![constant(*a), constant(*b)])
+            .collect();
+        let targets = pairs.iter().map(|(a, b)| vec![constant(a + b)]).collect();
+        (inputs, targets)
+    }
+
+    fn small_net() -> NeuralNet {
+        NeuralNet::from_weights(
+            vec![2, 3, 1],
+            vec![
+                vec![vec![0.3, -0.2], vec![0.1, 0.4], vec![-0.5, 0.2]],
+                vec![vec![0.2, 0.6, -0.3]],
+            ],
+            vec![vec![0.0, 0.0, 0.0], vec![0.0]],
+        )
+    }
+
+    #[test]
+    fn numerical_training_matches_autodiff_training() {
+        let (inputs, targets) = main_dataset();
+        let (numerical, autodiff) = (small_net(), small_net());
+        let mut optimizer = Sgd::new(autodiff.parameters(), 0.001);
+        let losses = autodiff
+            .train_full_batch(
+                &inputs,
+                &targets,
+                300,
+                &Mse,
+                &mut optimizer,
+                TrainOptions::default(),
+            )
+            .unwrap();
+        for _ in 0..300 {
+            numerical.train_numerical(|net| net.evaluate(&inputs, &targets, &Mse), 0.001, 1e-2);
+        }
+        assert!(losses.last().unwrap() < &(losses[0] / 100.0));
+        for (n, a) in zip(parameter_data(&numerical), parameter_data(&autodiff)) {
+            assert!((n - a).abs() < 1e-2, "{} != {}", n, a);
+        }
+    }
+
+    #[test]
+    fn max_pool_selects_the_maxima() {
+        let input: Vec<Value> = [1.0, 3.0, 2.0, 5.0, 4.0].into_iter().map(param).collect();
+        let pooled = max_pool1d(&input, 2, 2);
+        let data: Vec<f32> = pooled.iter().map(|v| v.data()).collect();
+        assert_eq!(data, vec![3.0, 5.0, 4.0]);
+        Value::sum_of(&pooled).compute_gradients();
+        let gradients: Vec<f32> = input.iter().map(|v| v.gradient()).collect();
+        assert_eq!(gradients, vec![0.0, 1.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn avg_pool_averages_each_window() {
+        let input: Vec<Value> = [1.0, 2.0, 3.0].into_iter().map(param).collect();
+        let pooled = avg_pool1d(&input, 2, 1);
+        let data: Vec<f32> = pooled.iter().map(|v| v.data()).collect();
+        assert_eq!(data, vec![1.5, 2.5]);
+        // Each window hands its elements an equal share of its gradient.
+        Value::sum_of(&pooled).compute_gradients();
+        let gradients: Vec<f32> = input.iter().map(|v| v.gradient()).collect();
+        assert_eq!(gradients, vec![0.5, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn reinit_with_a_seed_is_reproducible() {
+        let (a, b) = (NeuralNet::new(vec![2, 3, 1]), NeuralNet::new(vec![2, 3, 1]));
+        a.reinit(Some(7));
+        b.reinit(Some(7));
+        assert_eq!(parameter_data(&a), parameter_data(&b));
+        for layer in &a.layers {
+            assert!(layer.biases.iter().all(|b| b.data() == 0.0));
+        }
+        b.reinit(Some(8));
+        assert_ne!(parameter_data(&a), parameter_data(&b));
+    }
+
+    #[test]
+    fn reinit_repeats_the_chosen_initialization() {
+        let net = NeuralNet::new_with(vec![2, 3, 1], || 0.5);
+        net.parameters()[0].set_data(3.0);
+        net.reinit(Some(7));
+        assert!(parameter_data(&net).iter().all(|x| *x == 0.5));
+    }
+
+    #[test]
+    fn constant_inputs_widen_each_layer() {
+        let net = NeuralNet::with_constant_inputs(vec![3, 4, 2]);
+        assert_eq!(net.architecture(), vec![3, 4, 2]);
+        assert!(net.layers[0].weights.iter().all(|row| row.len() == 4));
+        assert!(net.layers[1].weights.iter().all(|row| row.len() == 5));
+        assert_eq!(net.layers[1].input_size(), 4);
+
+        for layer in &net.layers {
+            for w in layer.weights.iter().flatten() {
+                w.set_data(1.0);
+            }
+        }
+        // Each first layer neuron is 1 + (1 + 2 + 3), and each output is
+        // 1 + 4 * 7.
+        let inputs = vec![constant(1.0), constant(2.0), constant(3.0)];
+        let outputs: Vec<f32> = net.forward(inputs).iter().map(|v| v.data()).collect();
+        assert_eq!(outputs, vec![29.0, 29.0]);
+        assert_eq!(net.forward_eval(&[1.0, 2.0, 3.0]), vec![29.0, 29.0]);
+    }
+
+    #[test]
+    fn grad_norm_of_known_gradients() {
+        let net = NeuralNet::new(vec![2, 1]);
+        for (p, g) in zip(net.parameters(), [1.0, -2.0, 2.0]) {
+            p.set_gradient(g);
+        }
+        assert_eq!(net.grad_norm(), 3.0);
+    }
+
+    #[test]
+    fn param_norm_of_known_weights() {
+        let net = NeuralNet::from_weights(vec![2, 1], vec![vec![vec![3.0, 0.0]]], vec![vec![4.0]]);
+        assert_eq!(net.param_norm(), 5.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "a network needs at least an input and an output size")]
+    fn new_rejects_a_single_size() {
+        NeuralNet::new(vec![3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "layer sizes must be non-zero")]
+    fn new_rejects_zero_width_layers() {
+        NeuralNet::new(vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn new_accepts_heterogeneous_widths() {
+        let net = NeuralNet::new(vec![4, 1, 7, 2]);
+        assert_eq!(net.forward_eval(&[1.0, 2.0, 3.0, 4.0]).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "layer_sizes is empty")]
+    fn new_rejects_empty_layer_sizes() {
+        NeuralNet::new(vec![]);
+    }
+
+    #[test]
+    fn bounded_outputs_stay_within_bounds() {
+        let bounds = [(-1.0, 1.0), (10.0, 20.0)];
+        for x in [-100.0, -1.0, 0.0, 2.0, 100.0] {
+            let outputs = bounded(&[param(x), param(x)], &bounds);
+            for (output, (lo, hi)) in zip(&outputs, bounds) {
+                assert!(output.data() >= lo && output.data() <= hi);
+            }
+        }
+        let x = param(0.0);
+        let output = bounded(std::slice::from_ref(&x), &[(10.0, 20.0)]).remove(0);
+        output.compute_gradients();
+        assert_eq!(output.data(), 15.0);
+        // (hi - lo) * sigmoid'(0) = 10 * 0.25
+        assert!((x.gradient() - 2.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sum_balanced_is_accurate_and_shallow() {
+        let values: Vec<Value> = (0..100_000).map(|_| constant(0.1)).collect();
+        let exact: f64 = values.iter().map(|v| v.data() as f64).sum();
+        let naive = values.iter().fold(0.0f32, |sum, v| sum + v.data());
+        let balanced = sum_balanced(&values);
+        let naive_error = (naive as f64 - exact).abs();
+        let balanced_error = (balanced.data() as f64 - exact).abs();
+        assert!(
+            balanced_error < naive_error,
+            "{} >= {}",
+            balanced_error,
+            naive_error
+        );
+        // ceil(log2(100000)) = 17
+        assert_eq!(balanced.graph_depth(), 17);
+        assert_eq!(sum_balanced(&[]).data(), 0.0);
+    }
+
+    // The outputs and the gradients of the parameters and inputs after a
+    // backward pass from the sum of the outputs.
+    fn outputs_and_gradients(
+        net: &NeuralNet,
+        forward: impl Fn(Vec<Value>) -> Vec<Value>,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let inputs = vec![constant(0.5), constant(-1.0), constant(2.0)];
+        let outputs = forward(inputs.clone());
+        Value::sum_of(&outputs).compute_gradients();
+        let gradients = net
+            .parameters()
+            .iter()
+            .chain(&inputs)
+            .map(|v| v.gradient())
+            .collect();
+        (outputs.iter().map(|v| v.data()).collect(), gradients)
+    }
+
+    #[test]
+    fn matrix_forward_matches_per_neuron_forward() {
+        let net = NeuralNet::new(vec![3, 4, 2]).with_activations(
+            0,
+            vec![
+                Activation::Tanh,
+                Activation::Relu,
+                Activation::Sigmoid,
+                Activation::Linear,
+            ],
+        );
+        // A neuron at a time, each a chain of additions onto its bias.
+        let per_neuron = |inputs: Vec<Value>| {
+            net.layers.iter().fold(inputs, |inputs, layer| {
+                let neurons = zip(zip(&layer.weights, &layer.biases), &layer.activations);
+                neurons
+                    .map(|((row, bias), activation)| {
+                        let sum =
+                            zip(row, &inputs).fold(bias.clone(), |sum, (w, x)| sum.add(&w.mul(x)));
+                        activation.apply(&sum)
+                    })
+                    .collect()
+            })
+        };
+        let (expected, expected_gradients) = outputs_and_gradients(&net, per_neuron);
+        let (outputs, gradients) = outputs_and_gradients(&net, |inputs| net.forward(inputs));
+        for (a, b) in zip(
+            expected.iter().chain(&expected_gradients),
+            outputs.iter().chain(&gradients),
+        ) {
+            assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn summary_counts_parameters() {
+        let summary = NeuralNet::new(vec![2, 3, 1])
+            .with_activations(0, vec![Activation::Relu; 3])
+            .summary();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].contains("relu") && lines[1].trim_end().ends_with('9'));
+        assert!(lines[2].contains("none") && lines[2].trim_end().ends_with('4'));
+        // (2 + 1) * 3 + (3 + 1) * 1
+        assert_eq!(lines[3], "total params: 13");
+    }
+
+    #[test]
+    fn loss_scaling_gives_the_same_weights() {
+        let (inputs, targets) = main_dataset();
+        let (plain, scaled) = (small_net(), small_net());
+        for (net, loss_scale) in [(&plain, 1.0), (&scaled, 1000.0)] {
+            let mut optimizer = Sgd::new(net.parameters(), 0.001);
+            for _ in 0..50 {
+                let predicted: Vec<Vec<Value>> =
+                    inputs.iter().map(|i| net.forward(i.clone())).collect();
+                Mse.batch(&predicted, &targets)
+                    .compute_gradients_scaled(loss_scale);
+                optimizer.step();
+            }
+        }
+        for (a, b) in zip(parameter_data(&plain), parameter_data(&scaled)) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn loss_and_backward_populates_gradients() {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let loss = net.loss_and_backward(&inputs, &targets, &Mse);
+        assert!(net.parameters().iter().all(|p| p.gradient() != 0.0));
+        let expected = net.evaluate(&inputs, &targets, &Mse);
+        assert!((loss - expected).abs() <= 1e-5 * expected);
+    }
+
+    #[test]
+    fn balanced_forward_is_shallower() {
+        let net = NeuralNet::new_with(vec![64, 1], || 0.25);
+        let inputs: Vec<Value> = (0..64).map(|i| constant(i as f32)).collect();
+        let balanced = net.forward(inputs.clone()).remove(0);
+        let layer = &net.layers[0];
+        let chained = zip(&layer.weights[0], &inputs)
+            .map(|(w, x)| w.mul(x))
+            .reduce(|sum, product| sum.add(&product))
+            .unwrap()
+            .add(&layer.biases[0]);
+        assert_eq!(balanced.data(), chained.data());
+        // log2(64) additions, plus the multiplication and the bias.
+        assert_eq!(balanced.graph_depth(), 8);
+        assert_eq!(chained.graph_depth(), 65);
+    }
+
+    #[test]
+    fn broadcast_bias_gradients_sum_over_the_batch() {
+        let bias = vec![param(1.0), param(-1.0)];
+        let batch = vec![
+            vec![constant(1.0), constant(2.0)],
+            vec![constant(3.0), constant(4.0)],
+            vec![constant(5.0), constant(6.0)],
+        ];
+        let rows = broadcast_add(&batch, &bias);
+        assert_eq!(rows[2][0].data(), 6.0);
+        assert_eq!(rows[2][1].data(), 5.0);
+        // Weight the columns differently so each bias gets its own total.
+        let terms: Vec<Value> = rows
+            .iter()
+            .map(|row| row[0].add(&row[1].mul(&constant(2.0))))
+            .collect();
+        Value::sum_of(&terms).compute_gradients();
+        assert_eq!(bias[0].gradient(), 3.0);
+        assert_eq!(bias[1].gradient(), 6.0);
+    }
+
+    #[test]
+    fn constant_initializer() {
+        let net = NeuralNet::new_with(vec![3, 4, 2], || 0.5);
+        assert_eq!(net.parameters().len(), 4 * 4 + 2 * 5);
+        assert!(parameter_data(&net).iter().all(|x| *x == 0.5));
+    }
+
+    #[test]
+    fn from_weights_computes_the_dot_product_plus_bias() {
+        let net = NeuralNet::from_weights(
+            vec![3, 2],
+            vec![vec![vec![1.0, 2.0, 3.0], vec![-1.0, 0.5, 0.0]]],
+            vec![vec![0.5, -2.0]],
+        );
+        let outputs: Vec<f32> = net
+            .forward(vec![constant(1.0), constant(-1.0), constant(2.0)])
+            .iter()
+            .map(|v| v.data())
+            .collect();
+        // 1 - 2 + 6 + 0.5 and -1 - 0.5 + 0 - 2
+        assert_eq!(outputs, vec![5.5, -3.5]);
+        assert_eq!(net.layer_weights(0).1, vec![0.5, -2.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "layer 0 weight matrix should have 3 columns")]
+    fn from_weights_checks_shapes() {
+        NeuralNet::from_weights(vec![3, 1], vec![vec![vec![1.0, 2.0]]], vec![vec![0.0]]);
+    }
+
+    #[test]
+    fn clip_grad_value_only_clamps_large_gradients() {
+        let net = NeuralNet::new(vec![2, 2]);
+        let gradients = [5.0, -0.3, -7.0, 0.9, 1.0, 0.0];
+        for (p, g) in zip(net.parameters(), gradients) {
+            p.set_gradient(g);
+        }
+        net.clip_grad_value(1.0);
+        let clipped: Vec<f32> = net.parameters().iter().map(|p| p.gradient()).collect();
+        assert_eq!(clipped, vec![1.0, -0.3, -1.0, 0.9, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn evaluate_matches_the_batch_loss() {
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        let (inputs, targets) = main_dataset();
+        let predicted: Vec<Vec<Value>> = inputs.iter().map(|i| net.forward(i.clone())).collect();
+        let batch = Mse.batch(&predicted, &targets).data();
+        let evaluated = net.evaluate(&inputs, &targets, &Mse);
+        assert!((batch - evaluated).abs() <= 1e-5 * batch.max(1.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't evaluate on an empty dataset")]
+    fn evaluate_rejects_an_empty_dataset() {
+        NeuralNet::new(vec![2, 1]).evaluate(&[], &[], &Mse);
+    }
+
+    #[test]
+    fn snapshot_targets_leave_the_source_untouched() {
+        let (online, target) = (small_net(), NeuralNet::new_with(vec![2, 3, 1], || 0.1));
+        let input = vec![constant(1.0), constant(2.0)];
+        let targets = vec![target.snapshot_forward(input.clone())];
+        let predicted = vec![online.forward(input)];
+        Mse.batch(&predicted, &targets).compute_gradients();
+        assert!(target.parameters().iter().all(|p| p.gradient() == 0.0));
+        assert!(online.parameters().iter().any(|p| p.gradient() != 0.0));
+
+        let before = parameter_data(&target);
+        Sgd::new(online.parameters(), 0.1).step();
+        assert_eq!(parameter_data(&target), before);
+    }
+
+    #[test]
+    fn full_batch_training_reduces_the_loss() {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = Sgd::new(net.parameters(), 0.001);
+        let losses = net
+            .train_full_batch(
+                &inputs,
+                &targets,
+                200,
+                &Mse,
+                &mut optimizer,
+                TrainOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(losses.len(), 200);
+        assert!(losses[199] < losses[0] / 10.0);
+        let prediction = net.forward(vec![constant(9.0), constant(4.0)])[0].data();
+        assert!((prediction - 13.0).abs() < 1.0, "{}", prediction);
+    }
+
+    #[test]
+    fn training_stops_once_the_loss_diverges() {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = Sgd::new(net.parameters(), 10.0);
+        let losses = net
+            .train_full_batch(
+                &inputs,
+                &targets,
+                1000,
+                &Mse,
+                &mut optimizer,
+                TrainOptions::default(),
+            )
+            .unwrap();
+        assert!(losses.len() < 1000);
+        assert!(!losses.last().unwrap().is_finite());
+        assert!(losses[..losses.len() - 1].iter().all(|l| l.is_finite()));
+    }
+
+    #[test]
+    fn training_can_continue_after_the_loss_diverges() {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = Sgd::new(net.parameters(), 10.0);
+        let options = TrainOptions {
+            stop_on_non_finite: false,
+            ..Default::default()
+        };
+        let losses = net
+            .train_full_batch(&inputs, &targets, 100, &Mse, &mut optimizer, options)
+            .unwrap();
+        assert_eq!(losses.len(), 100);
+        assert!(!losses.last().unwrap().is_finite());
+    }
+
+    #[test]
+    fn sum_kahan_recovers_lost_precision() {
+        let values: Vec<Value> = [1.0]
+            .into_iter()
+            .chain([1e-8; 10_000])
+            .map(constant)
+            .collect();
+        let naive = values.iter().fold(0.0f32, |sum, v| sum + v.data());
+        let kahan = sum_kahan(&values);
+        assert_eq!(naive, 1.0);
+        assert!((kahan.data() - 1.0001).abs() < 1e-6);
+
+        kahan.compute_gradients();
+        assert!(values.iter().all(|v| v.gradient() == 1.0));
+    }
+
+    #[test]
+    fn sum_kahan_of_one_value_is_a_new_value() {
+        let x = param(2.0);
+        let total = sum_kahan(std::slice::from_ref(&x));
+        assert_ne!(total.id(), x.id());
+        assert_eq!(total.data(), 2.0);
+        // The input isn't marked as changed, so nothing depending on it is
+        // recomputed.
+        assert_eq!(x.mul(&constant(3.0)).recompute(), 0);
+    }
+
+    #[test]
+    fn recompute_keeps_the_kahan_sum() {
+        let values: Vec<Value> = [1.0]
+            .into_iter()
+            .chain([1e-8; 10_000])
+            .map(constant)
+            .collect();
+        let total = sum_kahan(&values);
+        let doubled = total.mul(&constant(2.0));
+        assert_eq!(doubled.recompute(), 0);
+        assert!((total.data() - 1.0001).abs() < 1e-6);
+        assert!((doubled.data() - 2.0002).abs() < 1e-6);
+    }
+
+    #[test]
+    fn layer_weights_shape_and_values() {
+        let net = NeuralNet::from_weights(
+            vec![3, 2, 1],
+            vec![
+                vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]],
+                vec![vec![7.0, 8.0]],
+            ],
+            vec![vec![0.1, 0.2], vec![0.3]],
+        );
+        let (weights, biases) = net.layer_weights(0);
+        assert_eq!(weights, vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        assert_eq!(biases, vec![0.1, 0.2]);
+        assert_eq!(net.layer_weights(1), (vec![vec![7.0, 8.0]], vec![0.3]));
+
+        // The constant input's weight is the first column.
+        let (weights, _) = NeuralNet::with_constant_inputs(vec![3, 2]).layer_weights(0);
+        assert_eq!((weights.len(), weights[0].len()), (2, 4));
+    }
+
+    #[test]
+    fn input_gradients_of_a_linear_net() {
+        let net = NeuralNet::from_weights(
+            vec![3, 2],
+            vec![vec![vec![1.0, -2.0, 0.5], vec![3.0, 1.0, -1.5]]],
+            vec![vec![0.2, 0.1]],
+        );
+        let inputs = vec![constant(1.0), constant(2.0), constant(3.0)];
+        Value::sum_of(&net.forward(inputs.clone())).compute_gradients();
+        // Each input's gradient is the sum of the weights applied to it.
+        assert_eq!(net.input_gradients(&inputs), vec![4.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn activate_applies_each_activation() {
+        let inputs = [-2.0, 0.0, 3.0, 7.0];
+        let values: Vec<Value> = inputs.into_iter().map(param).collect();
+        for activation in [
+            Activation::Linear,
+            Activation::Relu,
+            Activation::Relu6,
+            Activation::Sigmoid,
+            Activation::Tanh,
+            Activation::Silu,
+        ] {
+            let outputs = activate(&values, activation);
+            for (output, x) in zip(&outputs, inputs) {
+                let expected = activation.apply_data(x);
+                assert!((output.data() - expected).abs() < 1e-6, "{:?}", activation);
+            }
+        }
+        let relu: Vec<f32> = activate(&values, Activation::Relu)
+            .iter()
+            .map(|v| v.data())
+            .collect();
+        assert_eq!(relu, vec![0.0, 0.0, 3.0, 7.0]);
+        let relu6: Vec<f32> = activate(&values, Activation::Relu6)
+            .iter()
+            .map(|v| v.data())
+            .collect();
+        assert_eq!(relu6, vec![0.0, 0.0, 3.0, 6.0]);
+        assert_eq!(activate(&values, Activation::Sigmoid)[1].data(), 0.5);
+        assert_eq!(activate(&values, Activation::Linear)[0].data(), -2.0);
+    }
+
+    #[test]
+    fn biases_start_at_zero() {
+        for net in [
+            NeuralNet::new(vec![3, 4, 2]),
+            NeuralNet::with_constant_inputs(vec![3, 4, 2]),
+        ] {
+            for layer in &net.layers {
+                assert!(layer.biases.iter().all(|b| b.data() == 0.0));
+                assert!(layer.weights.iter().flatten().any(|w| w.data() != 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn sum_squared_matches_the_naive_sum() {
+        let weights: Vec<Value> = [1.0, -2.0, 0.5, 3.0].into_iter().map(param).collect();
+        let total = sum_squared(&weights);
+        let naive: f32 = weights.iter().map(|w| w.data() * w.data()).sum();
+        assert_eq!(total.data(), naive);
+        total.compute_gradients();
+        for w in &weights {
+            assert_eq!(w.gradient(), 2.0 * w.data());
+        }
+    }
+
+    #[test]
+    fn compiled_predictions_match_the_network() {
+        let net = NeuralNet::with_constant_inputs(vec![2, 4, 4, 3])
+            .with_activations(
+                0,
+                vec![
+                    Activation::Tanh,
+                    Activation::Relu,
+                    Activation::Silu,
+                    Activation::Relu6,
+                ],
+            )
+            .with_residual(1);
+        let compiled = net.compile();
+        for inputs in [[0.5, -1.0], [2.0, 3.0], [-4.0, 0.0]] {
+            let expected: Vec<f32> = net
+                .forward(inputs.iter().map(|x| constant(*x)).collect())
+                .iter()
+                .map(|v| v.data())
+                .collect();
+            for (a, b) in zip(expected, compiled.predict(&inputs)) {
+                assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+            }
+        }
+        // The copy is independent of later training.
+        let before = compiled.predict(&[1.0, 1.0]);
+        for p in net.parameters() {
+            p.set_data(0.0);
+        }
+        assert_eq!(compiled.predict(&[1.0, 1.0]), before);
+    }
+
+    #[test]
+    fn weight_stats_of_known_weights() {
+        let net = NeuralNet::from_weights(
+            vec![2, 2],
+            vec![vec![vec![1.0, 3.0], vec![-1.0, 5.0]]],
+            vec![vec![100.0, -100.0]],
+        );
+        // Biases are left out.
+        let stats = net.weight_stats();
+        assert_eq!((stats.min, stats.max, stats.mean), (-1.0, 5.0, 2.0));
+        assert_eq!(stats.std, 5.0f32.sqrt());
+    }
+
+    #[test]
+    fn soft_update_interpolates_towards_the_source() {
+        let (target, source) = (NeuralNet::new_with(vec![2, 3, 1], || 1.0), small_net());
+        target.soft_update(&source, 0.5);
+        for (t, s) in zip(parameter_data(&target), parameter_data(&source)) {
+            assert!((t - (1.0 + s) / 2.0).abs() < 1e-6);
+        }
+        target.soft_update(&source, 1.0);
+        assert_eq!(parameter_data(&target), parameter_data(&source));
+    }
+
+    #[test]
+    #[should_panic(expected = "networks must have the same architecture")]
+    fn soft_update_rejects_other_architectures() {
+        // Both have 13 parameters, laid out differently.
+        NeuralNet::new(vec![2, 3, 1]).soft_update(&NeuralNet::new(vec![4, 2, 1]), 0.5);
+    }
+
+    #[test]
+    fn train_one_drives_the_loss_down() {
+        let net = small_net();
+        let mut optimizer = Sgd::new(net.parameters(), 0.01);
+        let losses: Vec<f32> = (0..50)
+            .map(|_| net.train_one(&[1.0, 2.0], &[3.0], &Mse, &mut optimizer))
+            .collect();
+        assert!(losses.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(losses[49] < losses[0] / 100.0);
+    }
+
+    #[test]
+    fn architecture_round_trips() {
+        for sizes in [vec![2, 1], vec![2, 3, 7, 1], vec![5, 5, 5]] {
+            assert_eq!(NeuralNet::new(sizes.clone()).architecture(), sizes);
+            assert_eq!(
+                NeuralNet::with_constant_inputs(sizes.clone()).architecture(),
+                sizes
+            );
+        }
+    }
+
+    #[test]
+    fn zero_weight_residual_block_is_the_identity() {
+        let net = NeuralNet::new_with(vec![3, 3], || 0.0).with_residual(0);
+        let inputs = [1.5, -2.0, 0.25];
+        let outputs: Vec<f32> = net
+            .forward(inputs.iter().map(|x| constant(*x)).collect())
+            .iter()
+            .map(|v| v.data())
+            .collect();
+        assert_eq!(outputs, inputs.to_vec());
+        assert_eq!(net.forward_eval(&inputs), inputs.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "a residual layer needs matching input and output sizes")]
+    fn residual_layers_need_matching_sizes() {
+        NeuralNet::new(vec![3, 2]).with_residual(0);
+    }
+
+    #[test]
+    fn grad_sign_flip_ratio_of_constructed_gradients() {
+        let net = NeuralNet::new(vec![3, 1]);
+        let set_gradients = |gradients: [f32; 4]| {
+            for (p, g) in zip(net.parameters(), gradients) {
+                p.set_gradient(g);
+            }
+        };
+        set_gradients([1.0, -1.0, 2.0, 0.0]);
+        assert_eq!(net.grad_sign_flip_ratio(), 0.0);
+        // The first two flip, the third keeps its sign and the zero never
+        // counts.
+        set_gradients([-1.0, 3.0, 0.5, -2.0]);
+        assert_eq!(net.grad_sign_flip_ratio(), 0.5);
+        set_gradients([-1.0, -3.0, -0.5, 2.0]);
+        assert_eq!(net.grad_sign_flip_ratio(), 0.75);
+    }
+
+    #[test]
+    fn training_with_mae_reaches_a_low_loss() {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = Sgd::new(net.parameters(), 0.002);
+        let losses = net
+            .train_full_batch(
+                &inputs,
+                &targets,
+                2000,
+                &Mae,
+                &mut optimizer,
+                TrainOptions::default(),
+            )
+            .unwrap();
+        let loss = *losses.last().unwrap();
+        assert!(loss < 0.5, "{}", loss);
+        assert!(loss < losses[0] / 10.0);
+    }
+
+    // Train `small_net` on the main.rs examples with `optimizer`, through the
+    // `Optimizer` trait, and return the first and last losses.
+    fn train_with<O: Optimizer>(make: impl Fn(Vec<Value>) -> O) -> (f32, f32) {
+        let net = small_net();
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = make(net.parameters());
+        let losses = net
+            .train_full_batch(
+                &inputs,
+                &targets,
+                200,
+                &Mse,
+                &mut optimizer,
+                TrainOptions::default(),
+            )
+            .unwrap();
+        (losses[0], losses[199])
+    }
+
+    #[test]
+    fn training_works_with_any_optimizer() {
+        for (first, last) in [
+            train_with(|p| Sgd::new(p, 0.001)),
+            train_with(|p| Adam::new(p, 0.05)),
+        ] {
+            assert!(last < first / 10.0, "{} -> {}", first, last);
+        }
+    }
+
+    #[test]
+    fn per_neuron_activations() {
+        // Both neurons compute x - 2.
+        let net = NeuralNet::from_weights(
+            vec![1, 2],
+            vec![vec![vec![1.0], vec![1.0]]],
+            vec![vec![-2.0, -2.0]],
+        )
+        .with_activations(0, vec![Activation::Relu, Activation::Tanh]);
+        let outputs: Vec<f32> = net
+            .forward(vec![constant(1.0)])
+            .iter()
+            .map(|v| v.data())
+            .collect();
+        assert_eq!(outputs, vec![0.0, (-1.0f32).tanh()]);
+        assert_eq!(net.forward_eval(&[1.0]), outputs);
+        assert_eq!(net.forward_eval(&[4.0]), vec![2.0, 2.0f32.tanh()]);
+        assert!(net.summary().contains("mixed"));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected one activation per neuron")]
+    fn activations_must_match_the_neuron_count() {
+        NeuralNet::new(vec![1, 2]).with_activations(0, vec![Activation::Relu]);
+    }
+
+    #[test]
+    fn verbose_controls_progress_output() {
+        let (inputs, targets) = main_dataset();
+        for (verbose, expected_lines) in [(0, 0), (1, 3), (2, 3)] {
+            let net = NeuralNet::new(vec![2, 3, 1]);
+            let mut optimizer = Sgd::new(net.parameters(), 0.001);
+            let mut log = Vec::new();
+            let options = TrainOptions {
+                verbose,
+                progress: Some(&mut log),
+                ..Default::default()
+            };
+            net.train_full_batch(&inputs, &targets, 3, &Mse, &mut optimizer, options)
+                .unwrap();
+            let log = String::from_utf8(log).unwrap();
+            assert_eq!(log.lines().count(), expected_lines, "{:?}", log);
+        }
+    }
+
+    #[test]
+    fn loss_log_has_a_row_per_epoch() {
+        let path =
+            std::env::temp_dir().join(format!("rustgrad_loss_log_{}.csv", std::process::id()));
+        let (inputs, targets) = main_dataset();
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        let mut optimizer = Sgd::new(net.parameters(), 0.001);
+        let options = TrainOptions {
+            log_path: path.to_str(),
+            ..Default::default()
+        };
+        let losses = net
+            .train_full_batch(&inputs, &targets, 4, &Mse, &mut optimizer, options)
+            .unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let rows: Vec<&str> = log.lines().collect();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0], "epoch,loss");
+        assert_eq!(rows[4], format!("3,{}", losses[3]));
+    }
+
+    #[test]
+    fn loss_log_errors_are_returned() {
+        let (inputs, targets) = main_dataset();
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        let mut optimizer = Sgd::new(net.parameters(), 0.001);
+        let options = TrainOptions {
+            log_path: Some("/nonexistent/rustgrad/loss.csv"),
+            ..Default::default()
+        };
+        assert!(net
+            .train_full_batch(&inputs, &targets, 1, &Mse, &mut optimizer, options)
+            .is_err());
+    }
+
+    #[test]
+    fn average_of_two_nets_is_the_midpoint() {
+        let a = NeuralNet::from_weights(vec![2, 1], vec![vec![vec![1.0, -2.0]]], vec![vec![0.5]]);
+        let b = NeuralNet::from_weights(vec![2, 1], vec![vec![vec![3.0, 2.0]]], vec![vec![-1.5]]);
+        let mean = a.average_with(&[&b]);
+        assert_eq!(parameter_data(&mean), vec![2.0, 0.0, -0.5]);
+        assert_eq!(parameter_data(&a), vec![1.0, -2.0, 0.5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "networks must have the same architecture")]
+    fn average_with_rejects_other_architectures() {
+        NeuralNet::new(vec![2, 1]).average_with(&[&NeuralNet::new(vec![2, 2, 1])]);
+    }
+
+    #[test]
+    fn flops_counts_one_multiply_add_per_weight() {
+        assert_eq!(NeuralNet::new(vec![2, 3, 1]).flops(), 9);
+        assert_eq!(NeuralNet::new(vec![4, 5, 5, 2]).flops(), 20 + 25 + 10);
+    }
+
+    #[test]
+    fn fused_forward_matches_the_decomposed_forward() {
+        let net = NeuralNet::new(vec![3, 4, 2])
+            .with_activations(1, vec![Activation::Tanh, Activation::Sigmoid]);
+        let (expected, expected_gradients) =
+            outputs_and_gradients(&net, |inputs| net.forward(inputs));
+        let (outputs, gradients) = outputs_and_gradients(&net, |inputs| net.forward_fused(inputs));
+        for (a, b) in zip(
+            expected.iter().chain(&expected_gradients),
+            outputs.iter().chain(&gradients),
+        ) {
+            assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+        }
+        let fused = net.forward_fused(vec![constant(0.5), constant(-1.0), constant(2.0)]);
+        assert!(fused.iter().all(|v| v.graph_depth() < 5));
+    }
+
+    #[test]
+    fn parameter_names_are_unique_and_follow_the_scheme() {
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        let names: Vec<String> = net
+            .named_parameters()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names.len(), 13);
+        assert_eq!(
+            names[..3],
+            [
+                "layer0.neuron0.weight0",
+                "layer0.neuron0.weight1",
+                "layer0.neuron0.bias"
+            ]
+        );
+        assert_eq!(names[12], "layer1.neuron0.bias");
+        let unique: std::collections::HashSet<&String> = names.iter().collect();
+        assert_eq!(unique.len(), names.len());
+    }
+
+    #[test]
+    fn freezing_a_layer_only_trains_the_others() {
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        net.freeze_matching("layer0");
+        for (name, p) in net.named_parameters() {
+            assert_eq!(p.is_param(), !name.starts_with("layer0"), "{}", name);
+        }
+        let before = parameter_data(&net);
+        let (inputs, targets) = main_dataset();
+        let mut optimizer = Sgd::new(net.parameters(), 0.01);
+        net.train_full_batch(
+            &inputs,
+            &targets,
+            1,
+            &Mse,
+            &mut optimizer,
+            TrainOptions::default(),
+        )
+        .unwrap();
+        for ((name, p), old) in zip(net.named_parameters(), before) {
+            if name.starts_with("layer0") {
+                assert_eq!(p.data(), old, "{}", name);
+            } else {
+                assert_ne!(p.data(), old, "{}", name);
+            }
+        }
+
+        net.unfreeze_matching("layer0");
+        assert!(net.parameters().iter().all(|p| p.is_param()));
+    }
+
+    #[test]
+    fn param_gradients_skip_a_frozen_layer() {
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        let (inputs, targets) = main_dataset();
+        let loss_of = |net: &NeuralNet| {
+            let predicted: Vec<Vec<Value>> =
+                inputs.iter().map(|i| net.forward(i.clone())).collect();
+            Mse.batch(&predicted, &targets)
+        };
+        let all_visited = loss_of(&net).compute_param_gradients();
+
+        net.freeze_matching("layer0");
+        let loss = loss_of(&net);
+        loss.compute_gradients();
+        let expected: Vec<f32> = net.parameters().iter().map(|p| p.gradient()).collect();
+        let visited = loss.compute_param_gradients();
+        assert!(visited < all_visited, "{} >= {}", visited, all_visited);
+        for ((name, p), gradient) in zip(net.named_parameters(), expected) {
+            if name.starts_with("layer0") {
+                assert_eq!(p.gradient(), 0.0, "{}", name);
+            } else {
+                assert_eq!(p.gradient(), gradient, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn numerical_training_skips_frozen_parameters() {
+        let net = NeuralNet::new(vec![2, 3, 1]);
+        net.freeze_matching("layer0");
+        let before = parameter_data(&net);
+        let (inputs, targets) = main_dataset();
+        net.train_numerical(|net| net.evaluate(&inputs, &targets, &Mse), 0.01, 1e-2);
+        for ((name, p), old) in zip(net.named_parameters(), before) {
+            if name.starts_with("layer0") {
+                assert_eq!(p.data(), old, "{}", name);
+            } else {
+                assert_ne!(p.data(), old, "{}", name);
             }
         }
     }
@@ -0,0 +1,503 @@
+// Optimizers which update parameters using the gradients computed by
+// `Value::compute_gradients`. Values that aren't parameters (see
+// `Value::is_param`) are left unchanged.
+
+use crate::value::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::iter::zip;
+
+// An update rule for a fixed set of parameters, so training doesn't depend
+// on a specific optimizer.
+pub trait Optimizer {
+    // Update each parameter using its current gradient.
+    fn step(&mut self);
+
+    // Reset the gradient of each parameter to zero.
+    fn zero_grad(&self);
+}
+
+// Plain stochastic gradient descent, moving each parameter by
+// `learning_rate` times its gradient.
+pub struct Sgd {
+    parameters: Vec<Value>,
+    learning_rate: f32,
+}
+
+impl Sgd {
+    pub fn new(parameters: Vec<Value>, learning_rate: f32) -> Sgd {
+        Sgd {
+            parameters,
+            learning_rate,
+        }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self) {
+        for p in &self.parameters {
+            p.learn(self.learning_rate);
+        }
+    }
+
+    fn zero_grad(&self) {
+        for p in &self.parameters {
+            p.set_gradient(0.0);
+        }
+    }
+}
+
+// Adam keeps exponential moving averages of each parameter's gradient and
+// squared gradient, and steps by `learning_rate * m / (sqrt(v) + epsilon)`
+// after correcting both averages for their bias towards zero early on.
+// With a weight decay (see `with_weight_decay`) this is AdamW.
+pub struct Adam {
+    parameters: Vec<Value>,
+    learning_rate: f32,
+    beta1: f32,
+    beta2: f32,
+    epsilon: f32,
+    weight_decay: f32,
+    moments: Vec<(f32, f32)>,
+    steps: i32,
+}
+
+impl Adam {
+    // An Adam optimizer with the usual defaults of beta1 = 0.9,
+    // beta2 = 0.999 and epsilon = 1e-8.
+    pub fn new(parameters: Vec<Value>, learning_rate: f32) -> Adam {
+        Adam::with_betas(parameters, learning_rate, 0.9, 0.999, 1e-8)
+    }
+
+    pub fn with_betas(
+        parameters: Vec<Value>,
+        learning_rate: f32,
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    ) -> Adam {
+        let moments = vec![(0.0, 0.0); parameters.len()];
+        Adam {
+            parameters,
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay: 0.0,
+            moments,
+            steps: 0,
+        }
+    }
+
+    // Shrink each parameter by `learning_rate * weight_decay` times its data
+    // on every step, decoupled from the gradient based update. Unlike adding
+    // an L2 penalty to the loss, the decay isn't rescaled by the moments, so
+    // every parameter decays at the same rate.
+    pub fn with_weight_decay(mut self, weight_decay: f32) -> Adam {
+        self.weight_decay = weight_decay;
+        self
+    }
+
+    // Save the step count and each parameter's moments to `path`, keyed by
+    // the parameter's label (see `NeuralNet::named_parameters`). The
+    // parameters themselves are not saved. Fails if two parameters share a
+    // label, e.g. when they come from two networks.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let moments = self.moments.iter().map(|(m, v)| vec![*m, *v]);
+        let mut lines = vec![self.steps.to_string()];
+        lines.extend(state_lines(&self.parameters, moments)?);
+        fs::write(path, lines.join("\n"))
+    }
+
+    // Restore the state written by `save`, reattaching each parameter's
+    // moments by its label, so the parameters may be given in any order.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines();
+        let steps = lines
+            .next()
+            .ok_or_else(|| invalid_data("missing the step count".to_string()))?
+            .parse::<i32>()
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let moments = parse_state(lines, &self.parameters, 2)?;
+        self.moments = moments.iter().map(|state| (state[0], state[1])).collect();
+        self.steps = steps;
+        Ok(())
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self) {
+        self.steps += 1;
+        let m_correction = 1.0 - self.beta1.powi(self.steps);
+        let v_correction = 1.0 - self.beta2.powi(self.steps);
+        for (p, (m, v)) in zip(&self.parameters, &mut self.moments) {
+            if !p.is_param() {
+                continue;
+            }
+            let gradient = p.gradient();
+            *m = self.beta1 * *m + (1.0 - self.beta1) * gradient;
+            *v = self.beta2 * *v + (1.0 - self.beta2) * gradient * gradient;
+            let m_hat = *m / m_correction;
+            let v_hat = *v / v_correction;
+            let decay = self.learning_rate * self.weight_decay * p.data();
+            p.set_data(
+                p.data() - decay - self.learning_rate * m_hat / (v_hat.sqrt() + self.epsilon),
+            );
+        }
+    }
+
+    fn zero_grad(&self) {
+        for p in &self.parameters {
+            p.set_gradient(0.0);
+        }
+    }
+}
+
+// AdaGrad keeps a running sum of the squared gradients of each parameter,
+// and scales each update by `learning_rate / (sqrt(sum) + epsilon)`.
+// Parameters with a history of large gradients therefore take smaller
+// steps, while rarely updated (sparse) parameters keep taking large ones.
+pub struct AdaGrad {
+    parameters: Vec<Value>,
+    learning_rate: f32,
+    epsilon: f32,
+    accumulators: Vec<f32>,
+}
+
+impl AdaGrad {
+    pub fn new(parameters: Vec<Value>, learning_rate: f32, epsilon: f32) -> AdaGrad {
+        let accumulators = vec![0.0; parameters.len()];
+        AdaGrad {
+            parameters,
+            learning_rate,
+            epsilon,
+            accumulators,
+        }
+    }
+
+    // The running sum of squared gradients for each parameter.
+    pub fn accumulators(&self) -> &[f32] {
+        &self.accumulators
+    }
+
+    // Save the accumulators to `path`, keyed by each parameter's label (see
+    // `NeuralNet::named_parameters`). The parameters themselves are not
+    // saved. Fails if two parameters share a label, e.g. when they come from
+    // two networks.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let accumulators = self.accumulators.iter().map(|a| vec![*a]);
+        let lines = state_lines(&self.parameters, accumulators)?;
+        fs::write(path, lines.join("\n"))
+    }
+
+    // Restore accumulators written by `save`, reattaching each one to the
+    // parameter with the same label, so the parameters may be given in any
+    // order.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let accumulators = parse_state(contents.lines(), &self.parameters, 1)?;
+        self.accumulators = accumulators.iter().map(|state| state[0]).collect();
+        Ok(())
+    }
+}
+
+impl Optimizer for AdaGrad {
+    fn step(&mut self) {
+        for (p, accumulator) in zip(&self.parameters, &mut self.accumulators) {
+            if !p.is_param() {
+                continue;
+            }
+            let gradient = p.gradient();
+            *accumulator += gradient * gradient;
+            let step = self.learning_rate / (accumulator.sqrt() + self.epsilon);
+            p.set_data(p.data() - step * gradient);
+        }
+    }
+
+    fn zero_grad(&self) {
+        for p in &self.parameters {
+            p.set_gradient(0.0);
+        }
+    }
+}
+
+// The key that a parameter's saved state is stored under: its label, or
+// its position for parameters without one.
+fn state_key(parameter: &Value, index: usize) -> String {
+    parameter.label().unwrap_or_else(|| format!("#{}", index))
+}
+
+// One line per parameter, made of its key and then its state, separated by
+// tabs. The keys must be unique, or the state couldn't be reattached to the
+// right parameters on load.
+fn state_lines(
+    parameters: &[Value],
+    state: impl Iterator<Item = Vec<f32>>,
+) -> io::Result<Vec<String>> {
+    let mut keys = HashSet::new();
+    zip(parameters.iter().enumerate(), state)
+        .map(|((index, p), values)| {
+            let key = state_key(p, index);
+            if !keys.insert(key.clone()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("more than one parameter has the key {}", key),
+                ));
+            }
+            let values: Vec<String> = values.iter().map(|x| x.to_string()).collect();
+            Ok(format!("{}\t{}", key, values.join("\t")))
+        })
+        .collect()
+}
+
+// Parse the lines written by `state_lines` and return the `width` values of
+// state of each of `parameters`, looked up by key. Every parameter must have
+// exactly one entry, and there must be no entries for other parameters.
+fn parse_state<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    parameters: &[Value],
+    width: usize,
+) -> io::Result<Vec<Vec<f32>>> {
+    let mut entries = HashMap::new();
+    for line in lines {
+        let mut fields = line.split('\t');
+        let key = fields.next().unwrap_or_default().to_string();
+        let values = fields
+            .map(|field| {
+                field
+                    .parse::<f32>()
+                    .map_err(|e| invalid_data(e.to_string()))
+            })
+            .collect::<io::Result<Vec<f32>>>()?;
+        if values.len() != width {
+            return Err(invalid_data(format!(
+                "expected {} values for {}, found {}",
+                width,
+                key,
+                values.len()
+            )));
+        }
+        if entries.insert(key.clone(), values).is_some() {
+            return Err(invalid_data(format!("duplicate state for {}", key)));
+        }
+    }
+    if entries.len() != parameters.len() {
+        return Err(invalid_data(format!(
+            "expected state for {} parameters, found {}",
+            parameters.len(),
+            entries.len()
+        )));
+    }
+    parameters
+        .iter()
+        .enumerate()
+        .map(|(index, p)| {
+            let key = state_key(p, index);
+            entries
+                .remove(&key)
+                .ok_or_else(|| invalid_data(format!("no saved state for {}", key)))
+        })
+        .collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural_net::NeuralNet;
+    use crate::value::{constant, param};
+
+    #[test]
+    fn adam_weight_decay_shrinks_weights_multiplicatively() {
+        let w = param(2.0);
+        let mut adam = Adam::new(vec![w.clone()], 0.1).with_weight_decay(0.5);
+        for _ in 0..3 {
+            // No gradient, so only the decay moves the weight.
+            adam.zero_grad();
+            adam.step();
+        }
+        assert!((w.data() - 2.0 * 0.95f32.powi(3)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn adagrad_accumulates_and_converges_on_sparse_features() {
+        let weights = vec![param(0.0), param(0.0), param(0.0)];
+        let mut adagrad = AdaGrad::new(weights.clone(), 0.5, 1e-8);
+        // The third feature is only present in one example out of five.
+        let examples = [
+            ([1.0, 0.0, 0.0], 2.0),
+            ([0.0, 1.0, 0.0], -3.0),
+            ([1.0, 1.0, 0.0], -1.0),
+            ([0.0, 1.0, 0.0], -3.0),
+            ([0.0, 0.0, 1.0], 0.5),
+        ];
+        let mut previous = adagrad.accumulators().to_vec();
+        for step in 0..1000 {
+            let (features, target) = examples[step % examples.len()];
+            let terms: Vec<Value> = zip(&weights, features)
+                .map(|(w, x)| w.mul(&constant(x)))
+                .collect();
+            Value::sum_of(&terms)
+                .subtract(&constant(target))
+                .squared()
+                .compute_gradients();
+            adagrad.step();
+            for (a, b) in zip(&previous, adagrad.accumulators()) {
+                assert!(b >= a);
+            }
+            previous = adagrad.accumulators().to_vec();
+        }
+        for (w, expected) in zip(&weights, [2.0, -3.0, 0.5]) {
+            assert!(
+                (w.data() - expected).abs() < 1e-2,
+                "{} != {}",
+                w.data(),
+                expected
+            );
+        }
+    }
+
+    // Labeled parameters, and the loss sum((c_i * p_i)^2) on them.
+    fn labeled_params(data: &[f32]) -> Vec<Value> {
+        data.iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let p = param(*x);
+                p.set_label(&format!("p{}", i));
+                p
+            })
+            .collect()
+    }
+
+    fn loss_of(params: &[Value]) -> Value {
+        let terms: Vec<Value> = params
+            .iter()
+            .enumerate()
+            .map(|(i, p)| p.mul(&constant(i as f32 + 1.0)).squared())
+            .collect();
+        Value::sum_of(&terms)
+    }
+
+    fn state_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rustgrad_{}_{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn adagrad_resumes_from_saved_state() {
+        let path = state_path("adagrad_state");
+        let original = labeled_params(&[1.0, -2.0, 0.5]);
+        let mut adagrad = AdaGrad::new(original.clone(), 0.1, 1e-8);
+        for _ in 0..3 {
+            loss_of(&original).compute_gradients();
+            adagrad.step();
+        }
+        adagrad.save(&path).unwrap();
+
+        let resumed = labeled_params(&original.iter().map(|p| p.data()).collect::<Vec<f32>>());
+        let mut reversed = resumed.clone();
+        reversed.reverse();
+        let mut resumed_adagrad = AdaGrad::new(reversed, 0.1, 1e-8);
+        resumed_adagrad.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        loss_of(&original).compute_gradients();
+        adagrad.step();
+        loss_of(&resumed).compute_gradients();
+        resumed_adagrad.step();
+        for (a, b) in zip(&original, &resumed) {
+            assert_eq!(a.data(), b.data());
+        }
+    }
+
+    #[test]
+    fn adam_resumes_from_saved_state() {
+        let path = state_path("adam_state");
+        let original = labeled_params(&[1.0, -2.0, 0.5]);
+        let mut adam = Adam::new(original.clone(), 0.1);
+        for _ in 0..3 {
+            loss_of(&original).compute_gradients();
+            adam.step();
+        }
+        adam.save(&path).unwrap();
+
+        // The resumed parameters are given in a different order, and are
+        // matched up by their labels.
+        let resumed = labeled_params(&original.iter().map(|p| p.data()).collect::<Vec<f32>>());
+        let mut reversed = resumed.clone();
+        reversed.reverse();
+        let mut resumed_adam = Adam::new(reversed, 0.1);
+        resumed_adam.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        loss_of(&original).compute_gradients();
+        adam.step();
+        loss_of(&resumed).compute_gradients();
+        resumed_adam.step();
+        for (a, b) in zip(&original, &resumed) {
+            assert_eq!(a.data(), b.data());
+        }
+    }
+
+    #[test]
+    fn load_rejects_state_for_other_parameters() {
+        let path = state_path("mismatched_state");
+        AdaGrad::new(labeled_params(&[1.0, 2.0]), 0.1, 1e-8)
+            .save(&path)
+            .unwrap();
+        let renamed = labeled_params(&[1.0, 2.0]);
+        renamed[1].set_label("other");
+        let missing = AdaGrad::new(renamed, 0.1, 1e-8).load(&path);
+        let fewer = AdaGrad::new(labeled_params(&[1.0]), 0.1, 1e-8).load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("no saved state for other"));
+        assert!(fewer.is_err());
+    }
+
+    #[test]
+    fn save_rejects_parameters_with_the_same_key() {
+        let path = state_path("colliding_state");
+        let (a, b) = (NeuralNet::new(vec![2, 1]), NeuralNet::new(vec![2, 1]));
+        let mut parameters = a.parameters();
+        parameters.extend(b.parameters());
+        let adagrad = AdaGrad::new(parameters.clone(), 0.1, 1e-8).save(&path);
+        let adam = Adam::new(parameters, 0.1).save(&path);
+        for result in [adagrad, adam] {
+            let error = result.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(error.to_string().contains("layer0.neuron0.weight0"));
+        }
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    // Take one step of `optimizer` on w * x, where only `w` is a parameter,
+    // and return the new data of both.
+    fn step_once(make: impl Fn(Vec<Value>) -> Box<dyn Optimizer>) -> (f32, f32) {
+        let w = param(2.0);
+        let x = constant(3.0);
+        let mut optimizer = make(vec![w.clone(), x.clone()]);
+        w.mul(&x).compute_gradients();
+        optimizer.step();
+        (w.data(), x.data())
+    }
+
+    #[test]
+    fn only_params_change_after_a_step() {
+        for (w, x) in [
+            step_once(|p| Box::new(Sgd::new(p, 0.1))),
+            step_once(|p| Box::new(Adam::new(p, 0.1))),
+            step_once(|p| Box::new(AdaGrad::new(p, 0.1, 1e-8))),
+        ] {
+            assert!(w < 2.0);
+            assert_eq!(x, 3.0);
+        }
+    }
+}
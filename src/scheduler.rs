@@ -0,0 +1,104 @@
+// Learning rate schedules, mapping a training step to the learning rate
+// that should be used at that step.
+
+pub enum Schedule {
+    // The same learning rate at every step.
+    Constant(f32),
+    // Linearly ramp the learning rate from 0 up to the initial learning rate
+    // of `base` over the first `steps` steps, then hand off to `base`. The
+    // `base` schedule starts counting from 0 once the warmup is over.
+    Warmup { steps: usize, base: Box<Schedule> },
+}
+
+impl Schedule {
+    pub fn lr_at(&self, step: usize) -> f32 {
+        match self {
+            Schedule::Constant(lr) => *lr,
+            Schedule::Warmup { steps, base } => {
+                if step < *steps {
+                    base.lr_at(0) * (step as f32) / (*steps as f32)
+                } else {
+                    base.lr_at(step - steps)
+                }
+            }
+        }
+    }
+}
+
+// Drops the learning rate when the loss stops improving. Unlike `Schedule`
+// this depends on the training progress, so it has to be fed the loss of
+// each epoch with `step`. Once `patience` epochs in a row fail to improve on
+// the best loss seen so far, the learning rate is multiplied by `factor`
+// (but never drops below `min_lr`) and the count starts over.
+pub struct ReduceOnPlateau {
+    lr: f32,
+    factor: f32,
+    patience: usize,
+    min_lr: f32,
+    best: f32,
+    bad_epochs: usize,
+}
+
+impl ReduceOnPlateau {
+    pub fn new(lr: f32, factor: f32, patience: usize, min_lr: f32) -> ReduceOnPlateau {
+        ReduceOnPlateau {
+            lr,
+            factor,
+            patience,
+            min_lr,
+            best: f32::INFINITY,
+            bad_epochs: 0,
+        }
+    }
+
+    pub fn lr(&self) -> f32 {
+        self.lr
+    }
+
+    // Record the loss of the latest epoch and return the learning rate to
+    // use for the next one.
+    pub fn step(&mut self, loss: f32) -> f32 {
+        if loss < self.best {
+            self.best = loss;
+            self.bad_epochs = 0;
+        } else {
+            self.bad_epochs += 1;
+            if self.bad_epochs >= self.patience {
+                self.lr = (self.lr * self.factor).max(self.min_lr);
+                self.bad_epochs = 0;
+            }
+        }
+        self.lr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warmup_ramps_up_to_the_base_rate() {
+        let schedule = Schedule::Warmup {
+            steps: 10,
+            base: Box::new(Schedule::Constant(0.1)),
+        };
+        assert_eq!(schedule.lr_at(0), 0.0);
+        assert!((schedule.lr_at(5) - 0.05).abs() < 1e-7);
+        assert_eq!(schedule.lr_at(10), 0.1);
+        assert_eq!(schedule.lr_at(100), 0.1);
+    }
+
+    #[test]
+    fn plateau_drops_the_rate_after_patience_epochs() {
+        let mut scheduler = ReduceOnPlateau::new(0.1, 0.5, 2, 0.02);
+        let losses = [1.0, 0.8, 0.8, 0.9, 0.7, 0.7, 0.7, 0.7, 0.7, 0.7];
+        let rates: Vec<f32> = losses.iter().map(|loss| scheduler.step(*loss)).collect();
+        // The loss stops improving after epochs 1 and 4, and the rate never
+        // drops below 0.02.
+        assert_eq!(
+            rates,
+            vec![0.1, 0.1, 0.1, 0.05, 0.05, 0.05, 0.025, 0.025, 0.02, 0.02]
+        );
+        assert_eq!(scheduler.lr(), 0.02);
+    }
+}
@@ -1,4 +1,6 @@
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::rc::Rc;
 
 // `Value` is essentially just a wrapper around a floating point
@@ -32,16 +34,44 @@ pub struct Value {
     body: Rc<RefCell<ValueBody>>,
 }
 
-// Creates a new `Value` object from a constant.
+// Creates a new `Value` object from a constant. The value is trainable, the
+// same as `param`; prefer `param` or `constant` to make the intent clear.
 pub fn value(x: f32) -> Value {
+    param(x)
+}
+
+// Creates a trainable leaf, e.g. a weight, which `learn` and the optimizers
+// update.
+pub fn param(x: f32) -> Value {
+    leaf(x, true)
+}
+
+// Creates a fixed leaf, e.g. an input or a target. It still receives a
+// gradient, but `learn` and the optimizers never change it.
+pub fn constant(x: f32) -> Value {
+    leaf(x, false)
+}
+
+fn leaf(x: f32, trainable: bool) -> Value {
     Value::new(ValueBody {
         data: x,
         children: Vec::new(),
         gradient: 0.0,
         operation: None,
+        label: None,
+        dirty: false,
+        trainable,
     })
 }
 
+// Creates a new `Value` object from a constant, with a name that is
+// included when dumping the operation graph.
+pub fn labeled(x: f32, label: &str) -> Value {
+    let v = value(x);
+    v.set_label(label);
+    v
+}
+
 impl Value {
     fn new(v: ValueBody) -> Value {
         Value {
@@ -49,84 +79,470 @@ impl Value {
         }
     }
 
+    // The value produced by applying `operation` to `children`.
+    fn from_operation(operation: Operation, children: Vec<Value>) -> Value {
+        let inputs: Vec<f32> = children.iter().map(|child| child.data()).collect();
+        Value::new(ValueBody {
+            data: operation
+                .evaluate(&inputs)
+                .expect("operation has no forward function"),
+            children,
+            gradient: 0.0,
+            operation: Some(operation),
+            label: None,
+            dirty: false,
+            trainable: false,
+        })
+    }
+
     pub fn data(&self) -> f32 {
         self.body.borrow().data
     }
 
+    // An identifier for this value that is shared by all of its clones, e.g.
+    // for the keys of `clone_subgraph`'s leaf map.
+    pub fn id(&self) -> *const () {
+        Rc::as_ptr(&self.body) as *const ()
+    }
+
+    // Overwrite the underlying data of this value. Note that this does not
+    // recompute any values that were previously derived from it, but marks
+    // this value as changed so that `recompute` can bring them up to date.
+    pub fn set_data(&self, x: f32) {
+        let mut body = self.body.borrow_mut();
+        body.data = x;
+        body.dirty = true;
+    }
+
+    // Bring the data of this value, and of every value in the operation graph
+    // below it, up to date after some of their inputs were changed with
+    // `set_data` (or `learn`). Only values that depend on a changed value are
+    // re-evaluated; the rest of the graph is skipped. Returns the number of
+    // values that were re-evaluated. Custom operations (see `custom`) have no
+    // forward function, so they keep their data.
+    //
+    // The changed markers are cleared afterwards. If a changed value is also
+    // part of another graph, recompute that graph first.
+    pub fn recompute(&self) -> usize {
+        let mut changed = HashSet::new();
+        let mut recomputed = 0;
+        for v in &self.topological_order() {
+            let mut body = v.body.borrow_mut();
+            let stale = body
+                .children
+                .iter()
+                .any(|child| changed.contains(&Rc::as_ptr(&child.body)));
+            if stale {
+                let inputs: Vec<f32> = body.children.iter().map(|child| child.data()).collect();
+                if let Some(data) = body.operation.as_ref().unwrap().evaluate(&inputs) {
+                    body.data = data;
+                    recomputed += 1;
+                }
+            }
+            if stale || body.dirty {
+                changed.insert(Rc::as_ptr(&v.body));
+            }
+            body.dirty = false;
+        }
+        recomputed
+    }
+
+    // The operation that produced this value, or None for leaves.
+    pub fn operation(&self) -> Option<Operation> {
+        self.body.borrow().operation.clone()
+    }
+
+    // The name of the operation that produced this value, or None for leaves.
+    pub fn op_name(&self) -> Option<&'static str> {
+        self.body.borrow().operation.as_ref().map(|op| op.name())
+    }
+
+    // Whether the underlying data is neither infinite nor NaN.
+    pub fn is_finite(&self) -> bool {
+        self.data().is_finite()
+    }
+
+    // Whether this is a trainable leaf, i.e. one created by `param` (or
+    // `value`). Constants and the results of operations are not.
+    pub fn is_param(&self) -> bool {
+        self.body.borrow().trainable
+    }
+
+    // Freeze (false) or unfreeze (true) a leaf, turning it into a constant or
+    // back into a parameter. Only leaves can be trained, so this panics for
+    // the results of operations.
+    pub fn set_trainable(&self, trainable: bool) {
+        let mut body = self.body.borrow_mut();
+        assert!(
+            body.children.is_empty(),
+            "only leaves can be made trainable"
+        );
+        body.trainable = trainable;
+    }
+
+    pub fn label(&self) -> Option<String> {
+        self.body.borrow().label.clone()
+    }
+
+    // Name this value, so it can be identified in `dump` and `to_dot` output.
+    pub fn set_label(&self, label: &str) {
+        self.body.borrow_mut().label = Some(label.to_string());
+    }
+
     pub fn gradient(&self) -> f32 {
         self.body.borrow().gradient
     }
 
+    // Overwrite the gradient of this value, e.g. to clip it before an update.
+    pub fn set_gradient(&self, gradient: f32) {
+        self.body.borrow_mut().gradient = gradient;
+    }
+
     // Add the provided value.
     pub fn add(&self, v: &Value) -> Value {
-        Value::new(ValueBody {
-            data: self.data() + v.data(),
-            children: vec![self.clone(), v.clone()],
-            gradient: 0.0,
-            operation: Some(Operation::Addition),
-        })
+        Value::from_operation(Operation::Addition, vec![self.clone(), v.clone()])
+    }
+
+    // The sum of all of the values as a single operation with one child per
+    // value. Its backward pass hands every child the full gradient in one
+    // step, instead of going through a chain (or tree) of additions.
+    pub fn sum_of(values: &[Value]) -> Value {
+        Value::from_operation(Operation::Sum, values.to_vec())
+    }
+
+    // A `Sum` of the values whose data is `data` instead of the evaluated
+    // sum, for `sum_kahan`, which computes the sum more precisely. Unlike
+    // `set_data`, this doesn't mark the value as changed.
+    pub(crate) fn sum_with_data(values: &[Value], data: f32) -> Value {
+        let sum = Value::sum_of(values);
+        sum.body.borrow_mut().data = data;
+        sum
+    }
+
+    // The product of all of the values as a single operation with one child
+    // per value.
+    pub fn product_of(values: &[Value]) -> Value {
+        Value::from_operation(Operation::Product, values.to_vec())
+    }
+
+    // The dot product of `weights` and `inputs` plus `bias`, as a single
+    // fused operation. The result is computed on plain floats, and only one
+    // value is added to the graph rather than one per multiplication and
+    // addition. Its children are the weights, then the inputs, then the bias.
+    pub fn linear(weights: &[Value], inputs: &[Value], bias: &Value) -> Value {
+        assert_eq!(weights.len(), inputs.len(), "expected one weight per input");
+        let children = weights
+            .iter()
+            .chain(inputs)
+            .chain([bias])
+            .cloned()
+            .collect();
+        Value::from_operation(Operation::Linear, children)
     }
 
     // Subtract the provided value from this value.
     pub fn subtract(&self, v: &Value) -> Value {
-        let negative = value(-1.0);
+        let negative = constant(-1.0);
         let neg_v = v.mul(&negative);
         self.add(&neg_v)
     }
 
-    // Raise this value to the provided power.
+    // Raise this value to the provided power. A negative base is only
+    // defined for integer exponents, e.g. (-2)^3 = -8, which `powf` already
+    // computes exactly, as does the gradient w.r.t. the base. With any other
+    // exponent the result (and its gradient) is NaN. The exponent only
+    // receives a gradient for positive bases, see `propagate_gradient`.
     pub fn pow(&self, p: &Value) -> Value {
-        Value::new(ValueBody {
-            data: self.data().powf(p.data()),
-            children: vec![self.clone(), p.clone()],
-            gradient: 0.0,
-            operation: Some(Operation::Power),
-        })
+        Value::from_operation(Operation::Power, vec![self.clone(), p.clone()])
     }
 
     // Square this value.
     pub fn squared(&self) -> Value {
-        let exponent = value(2.0);
+        let exponent = constant(2.0);
         self.pow(&exponent)
     }
 
     // Multiply this value by the provided value.
     pub fn mul(&self, v: &Value) -> Value {
-        Value::new(ValueBody {
-            data: self.data() * v.data(),
-            children: vec![self.clone(), v.clone()],
-            gradient: 0.0,
-            operation: Some(Operation::Multiplication),
-        })
+        Value::from_operation(Operation::Multiplication, vec![self.clone(), v.clone()])
+    }
+
+    // Divide this value by the provided value.
+    pub fn div(&self, v: &Value) -> Value {
+        Value::from_operation(Operation::Division, vec![self.clone(), v.clone()])
+    }
+
+    // Linearly interpolate between this value and `other`:
+    // self * (1 - t) + other * t.
+    pub fn lerp(&self, other: &Value, t: f32) -> Value {
+        self.mul(&constant(1.0 - t)).add(&other.mul(&constant(t)))
+    }
+
+    // The larger of this value and the provided value.
+    pub fn max(&self, v: &Value) -> Value {
+        Value::from_operation(Operation::Max, vec![self.clone(), v.clone()])
     }
 
     pub fn relu(&self) -> Value {
+        Value::from_operation(Operation::Relu, vec![self.clone()])
+    }
+
+    // Relu capped at 6, i.e. min(max(x, 0), 6).
+    pub fn relu6(&self) -> Value {
+        Value::from_operation(Operation::Relu6, vec![self.clone()])
+    }
+
+    // -1 if this value is negative, 1 if it's positive and 0 otherwise.
+    // This is a hard threshold, so no gradient flows back through it.
+    pub fn sign(&self) -> Value {
+        Value::from_operation(Operation::Sign, vec![self.clone()])
+    }
+
+    // The absolute value, built as x * sign(x) so its gradient is sign(x).
+    pub fn abs(&self) -> Value {
+        self.mul(&self.sign())
+    }
+
+    // e raised to this value.
+    pub fn exp(&self) -> Value {
+        Value::from_operation(Operation::Exp, vec![self.clone()])
+    }
+
+    // e raised to min(self, max_input), so that large inputs can't overflow
+    // to infinity. The clamp is built as -max(-x, -max_input), so inputs
+    // above `max_input` receive no gradient, like any other clamp.
+    pub fn exp_clamped(&self, max_input: f32) -> Value {
+        let negative = constant(-1.0);
+        let clamped = self
+            .mul(&negative)
+            .max(&constant(-max_input))
+            .mul(&negative);
+        clamped.exp()
+    }
+
+    // The natural logarithm of this value.
+    pub fn ln(&self) -> Value {
+        Value::from_operation(Operation::Ln, vec![self.clone()])
+    }
+
+    // The logistic sigmoid of this value: 1 / (1 + e^-x).
+    pub fn sigmoid(&self) -> Value {
+        Value::from_operation(Operation::Sigmoid, vec![self.clone()])
+    }
+
+    // The hyperbolic tangent of this value.
+    pub fn tanh(&self) -> Value {
+        Value::from_operation(Operation::Tanh, vec![self.clone()])
+    }
+
+    // A cheaper approximation of `tanh`, using a rational (continued fraction)
+    // approximation clamped to [-1, 1]. The absolute error against `f32::tanh`
+    // is below 1e-4, and is largest around |x| = 5 where the approximation
+    // saturates.
+    pub fn tanh_fast(&self) -> Value {
+        Value::from_operation(Operation::TanhFast, vec![self.clone()])
+    }
+
+    // The swish / SiLU activation: x * sigmoid(x). Built out of `mul` and
+    // `sigmoid`, so the product rule gradient falls out of the backward pass.
+    pub fn silu(&self) -> Value {
+        self.mul(&self.sigmoid())
+    }
+
+    // The inverse tangent of this value.
+    pub fn atan(&self) -> Value {
+        Value::from_operation(Operation::Atan, vec![self.clone()])
+    }
+
+    // The hyperbolic sine of this value.
+    pub fn sinh(&self) -> Value {
+        Value::from_operation(Operation::Sinh, vec![self.clone()])
+    }
+
+    // The hyperbolic cosine of this value.
+    pub fn cosh(&self) -> Value {
+        Value::from_operation(Operation::Cosh, vec![self.clone()])
+    }
+
+    // The same value, but gradients stop here instead of flowing back into
+    // the graph that produced it. Useful for treating part of a shared graph
+    // as a constant, e.g. a trunk shared by several heads.
+    pub fn stop_gradient(&self) -> Value {
+        Value::from_operation(Operation::StopGradient, vec![self.clone()])
+    }
+
+    // The same value, but the gradient flowing back through it is clamped to
+    // [-max_abs, max_abs], e.g. to tame a single exploding activation.
+    pub fn clip_grad(&self, max_abs: f32) -> Value {
+        Value::from_operation(Operation::ClipGrad(max_abs), vec![self.clone()])
+    }
+
+    // An operation the crate doesn't provide: `data` is the already computed
+    // forward value, and `backward` is given the gradient of the result and
+    // returns the gradient to add to each of `children`, in order. E.g. for
+    // a custom square of `x` whose data is `d`:
+    //
+    // Value::custom(d * d, vec![x], Box::new(move |g| vec![2.0 * d * g]))
+    //
+    // `gradient_graph` calls `backward` with a gradient of 1 and treats the
+    // results as constants, so higher order derivatives through a custom
+    // operation are not available.
+    pub fn custom(
+        data: f32,
+        children: Vec<Value>,
+        backward: Box<dyn Fn(f32) -> Vec<f32>>,
+    ) -> Value {
         Value::new(ValueBody {
-            data: self.data().max(0.0),
-            children: vec![self.clone()],
+            data,
+            children,
             gradient: 0.0,
-            operation: Some(Operation::Relu),
+            operation: Some(Operation::Custom(BackwardFn(Rc::from(backward)))),
+            label: None,
+            dirty: false,
+            trainable: false,
         })
     }
 
+    // Gradient checkpointing: the value of `f` applied to `inputs`, without
+    // keeping the operation graph that `f` builds. Only the inputs and the
+    // result are stored, and `f` is run again during the backward pass to
+    // recompute the intermediate values it needs. This trades compute for
+    // memory in deep graphs, while giving the same gradients as calling `f`
+    // directly.
+    //
+    // `f` must only depend on the values it is given: any other values it
+    // captures, such as weights, must be passed in `inputs` to receive
+    // gradients.
+    pub fn checkpoint(inputs: &[Value], f: impl Fn(&[Value]) -> Value + 'static) -> Value {
+        Value::from_operation(
+            Operation::Checkpoint(CheckpointFn(Rc::new(f))),
+            inputs.to_vec(),
+        )
+    }
+
     // Compute the gradients of all values in the operation graph
-    // that contributed to this value.
-    pub fn compute_gradients(&mut self) -> () {
-        self.clear_gradients();
+    // that contributed to this value. Every gradient in the graph is reset
+    // first, so this never accumulates across calls.
+    //
+    // The backward pass only ever writes gradients: the data of every value
+    // in the graph is left exactly as it was, so this can be run as often as
+    // needed to inspect gradients. Parameters only change when an update is
+    // applied explicitly, with `learn`, `set_data` or an `Optimizer`.
+    pub fn compute_gradients(&self) {
+        self.compute_gradients_with(false);
+    }
+
+    // Same as `compute_gradients`, but when `accumulate` is set the gradients
+    // of the leaves (the parameters and inputs) are not reset, so repeated
+    // calls, e.g. one per micro-batch, add up into them. They are only cleared
+    // by an explicit call to `zero_grad`. The gradients of all other values
+    // are still reset, since they only belong to a single pass.
+    pub fn compute_gradients_with(&self, accumulate: bool) {
+        if accumulate {
+            for v in self.unique_values() {
+                if !v.body.borrow().children.is_empty() {
+                    v.body.borrow_mut().gradient = 0.0;
+                }
+            }
+        } else {
+            self.zero_grad();
+        }
         // Since this value is the "root" of the graph it
         // always has a gradient of 1.
         self.body.borrow_mut().gradient = 1.0;
-        self.compute_gradients_recursive();
+        self.backward();
+    }
+
+    // Same as `compute_gradients`, but the backward pass is run as if this
+    // value had been multiplied by `loss_scale`, and every gradient is divided
+    // by `loss_scale` afterwards. The resulting gradients are mathematically
+    // the same, but intermediate gradients are kept larger, which can keep
+    // tiny gradients from being lost to f32 rounding. `loss_scale` must be
+    // finite and non-zero.
+    pub fn compute_gradients_scaled(&self, loss_scale: f32) {
+        assert!(
+            loss_scale.is_finite() && loss_scale != 0.0,
+            "loss scale must be finite and non-zero"
+        );
+        self.zero_grad();
+        self.body.borrow_mut().gradient = loss_scale;
+        self.backward();
+        for v in self.unique_values() {
+            v.body.borrow_mut().gradient /= loss_scale;
+        }
+    }
+
+    // Same as `compute_gradients`, but the backward pass skips every part of
+    // the graph that doesn't lead to a parameter (see `is_param`), such as the
+    // inputs or a frozen part of a network. The gradients of the parameters
+    // are the same, but those of constants and of the skipped values are
+    // incomplete, which is why this isn't the default: e.g.
+    // `NeuralNet::input_gradients` needs the gradients of constant inputs.
+    // Returns the number of operations the gradient was propagated through.
+    pub fn compute_param_gradients(&self) -> usize {
+        let order = self.topological_order();
+        // Children come before their parents, so each value's children have
+        // already been checked when it is reached.
+        let mut leads_to_param = HashSet::new();
+        for v in &order {
+            let body = v.body.borrow();
+            if body.trainable
+                || body
+                    .children
+                    .iter()
+                    .any(|child| leads_to_param.contains(&child.id()))
+            {
+                leads_to_param.insert(v.id());
+            }
+        }
+        for v in &order {
+            v.body.borrow_mut().gradient = 0.0;
+        }
+        self.body.borrow_mut().gradient = 1.0;
+        let mut propagated = 0;
+        for v in order.iter().rev() {
+            if leads_to_param.contains(&v.id()) && !v.body.borrow().children.is_empty() {
+                v.propagate_gradient();
+                propagated += 1;
+            }
+        }
+        propagated
     }
 
-    // Assign the gradients to each child node and then recursively call this function
-    // for each child. Note that all gradient computations here multiply the result
-    // by self.gradient according to the power rule. In laymans terms, the power
-    // rule says that you can chain derivatives together using multiplication.
+    // Run the backward pass from this value and return the gradient of `wrt`,
+    // which is identified by identity rather than by its data. Values that
+    // are not part of this value's operation graph have a gradient of 0.
+    pub fn grad_of(&self, wrt: &Value) -> f32 {
+        let in_graph = self
+            .unique_values()
+            .iter()
+            .any(|v| Rc::ptr_eq(&v.body, &wrt.body));
+        if !in_graph {
+            return 0.0;
+        }
+        self.compute_gradients();
+        wrt.gradient()
+    }
+
+    // Propagate the gradient of this (already seeded) value down to every value
+    // in the operation graph. A value can be used by several others, so its
+    // gradient is only complete once all of them have contributed to it.
+    // Visiting the values in reverse topological order guarantees that, and
+    // means each value passes its gradient on exactly once.
+    fn backward(&self) {
+        for v in self.topological_order().iter().rev() {
+            v.propagate_gradient();
+        }
+    }
+
+    // Assign the gradients to each child node. Note that all gradient computations
+    // here multiply the result by self.gradient according to the chain rule. In
+    // laymans terms, the chain rule says that you can chain derivatives together
+    // using multiplication.
     //
     // Conretely: If a biker can move 2x as fast as a walker, and a car is 4x faster than
     // a biker, then a car is 2 * 4 = 8 times faster than a walker.
-    pub fn compute_gradients_recursive(&mut self) -> () {
+    fn propagate_gradient(&self) {
         match self.body.borrow().operation {
             // Addition operations are gradient propagators.
             // E.g. in  x = y + z, y and z's
@@ -138,6 +554,45 @@ impl Value {
                 }
             }
 
+            // A variable arity sum propagates the gradient just like addition.
+            Some(Operation::Sum) => {
+                for child in &self.body.borrow().children {
+                    child.body.borrow_mut().gradient += self.gradient();
+                }
+            }
+
+            // Each weight's gradient is its input and vice versa, as in
+            // multiplication, while the bias is added like in addition.
+            Some(Operation::Linear) => {
+                let children = &self.body.borrow().children;
+                let n = children.len() / 2;
+                let gradient = self.gradient();
+                for i in 0..n {
+                    let (w, x) = (children[i].data(), children[n + i].data());
+                    children[i].body.borrow_mut().gradient += x * gradient;
+                    children[n + i].body.borrow_mut().gradient += w * gradient;
+                }
+                children[2 * n].body.borrow_mut().gradient += gradient;
+            }
+
+            // Each child's gradient is the product of all of the other
+            // children. Rather than dividing the total product by the child,
+            // which breaks when a child is 0, it's built from the running
+            // products of the children before and after it.
+            Some(Operation::Product) => {
+                let children = &self.body.borrow().children;
+                let data: Vec<f32> = children.iter().map(|child| child.data()).collect();
+                let mut before = vec![1.0; data.len()];
+                for i in 1..data.len() {
+                    before[i] = before[i - 1] * data[i - 1];
+                }
+                let mut after = 1.0;
+                for i in (0..data.len()).rev() {
+                    children[i].body.borrow_mut().gradient += before[i] * after * self.gradient();
+                    after *= data[i];
+                }
+            }
+
             // Multiplication operations "swap" the gradients of the operands.
             // E.g. in x = y * z, y's gradient w.r.t. x is z, and z's gradient
             // w.r.t. x is y.
@@ -148,14 +603,43 @@ impl Value {
                 rhs.body.borrow_mut().gradient += lhs.data() * self.gradient();
             }
 
+            // In x = y / z, y's gradient w.r.t. x is 1 / z and z's gradient
+            // w.r.t. x is -y / z^2.
+            Some(Operation::Division) => {
+                let numerator = &self.body.borrow().children[0];
+                let denominator = &self.body.borrow().children[1];
+                numerator.body.borrow_mut().gradient += self.gradient() / denominator.data();
+                denominator.body.borrow_mut().gradient +=
+                    -numerator.data() / denominator.data().powi(2) * self.gradient();
+            }
+
             // Power operations follow the classic derivative rule of:
-            // dx/dy x = y^z => z * (y^(z-1))
-            // Or more concretely: dy/dx y^3 = 2y^2
+            // d/dy y^z => z * (y^(z-1))
+            // Or more concretely: d/dy y^3 = 3y^2
+            // The exponent's gradient is d/dz y^z => y^z * ln(y), which is only
+            // defined for positive bases, so other bases don't contribute one.
             Some(Operation::Power) => {
                 let base = &self.body.borrow().children[0];
                 let exponent = &self.body.borrow().children[1];
                 base.body.borrow_mut().gradient +=
                     exponent.data() * (base.data().powf(exponent.data() - 1.0)) * self.gradient();
+                if base.data() > 0.0 {
+                    exponent.body.borrow_mut().gradient +=
+                        self.data() * base.data().ln() * self.gradient();
+                }
+            }
+
+            // The max function simply selects one of its operands, so the
+            // selected operand receives all of the gradient and the other
+            // receives none. Ties are broken in favor of the left hand side.
+            Some(Operation::Max) => {
+                let lhs = &self.body.borrow().children[0];
+                let rhs = &self.body.borrow().children[1];
+                if lhs.data() >= rhs.data() {
+                    lhs.body.borrow_mut().gradient += self.gradient();
+                } else {
+                    rhs.body.borrow_mut().gradient += self.gradient();
+                }
             }
 
             // The Relu function derivative is: 1 if x > 0 else 0
@@ -168,41 +652,783 @@ impl Value {
                 };
             }
 
+            // Relu6 passes the gradient through only where it's linear,
+            // i.e. for 0 < x < 6, and is flat everywhere else.
+            Some(Operation::Relu6) => {
+                let base = &self.body.borrow().children[0];
+                if base.data() > 0.0 && base.data() < 6.0 {
+                    base.body.borrow_mut().gradient += self.gradient();
+                }
+            }
+
+            // Blocks the gradient by design, see `stop_gradient`.
+            Some(Operation::StopGradient) => (),
+
+            // Passes the gradient on, clamped, see `clip_grad`.
+            Some(Operation::ClipGrad(max_abs)) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += self.gradient().clamp(-max_abs, max_abs);
+            }
+
+            // The sign function is flat everywhere except at 0 where it's
+            // undefined, so its derivative is taken to be 0 and the child
+            // receives no gradient.
+            Some(Operation::Sign) => (),
+
+            // e^x is its own derivative.
+            Some(Operation::Exp) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += self.data() * self.gradient();
+            }
+
+            // The derivative of ln(x) is 1 / x.
+            Some(Operation::Ln) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += self.gradient() / base.data();
+            }
+
+            // The sigmoid derivative can be expressed in terms of its own
+            // output: d/dx sigmoid(x) = sigmoid(x) * (1 - sigmoid(x)).
+            Some(Operation::Sigmoid) => {
+                let base = &self.body.borrow().children[0];
+                let data = self.data();
+                base.body.borrow_mut().gradient += data * (1.0 - data) * self.gradient();
+            }
+
+            // Like the sigmoid, the tanh derivative can be expressed in terms
+            // of its own output: d/dx tanh(x) = 1 - tanh(x)^2. The fast
+            // approximation reuses the same rule with its approximated output.
+            Some(Operation::Tanh) | Some(Operation::TanhFast) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += (1.0 - self.data().powi(2)) * self.gradient();
+            }
+
+            // The derivative of atan(x) is 1 / (1 + x^2).
+            Some(Operation::Atan) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += self.gradient() / (1.0 + base.data().powi(2));
+            }
+
+            // The hyperbolic sine and cosine are each other's derivatives:
+            // d/dx sinh(x) = cosh(x) and d/dx cosh(x) = sinh(x).
+            Some(Operation::Sinh) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += base.data().cosh() * self.gradient();
+            }
+
+            Some(Operation::Cosh) => {
+                let base = &self.body.borrow().children[0];
+                base.body.borrow_mut().gradient += base.data().sinh() * self.gradient();
+            }
+
+            // Rebuild the checkpointed graph on fresh copies of the children
+            // and backpropagate through it, then hand each child the gradient
+            // of its copy.
+            Some(Operation::Checkpoint(ref f)) => {
+                let children = &self.body.borrow().children;
+                let leaves: Vec<Value> = children
+                    .iter()
+                    .map(|child| constant(child.data()))
+                    .collect();
+                (f.0)(&leaves).compute_gradients();
+                for (child, leaf) in std::iter::zip(children, &leaves) {
+                    child.body.borrow_mut().gradient += leaf.gradient() * self.gradient();
+                }
+            }
+
+            // Hand each child the gradient computed by the user's function.
+            Some(Operation::Custom(ref f)) => {
+                let children = &self.body.borrow().children;
+                let gradients = (f.0)(self.gradient());
+                assert_eq!(
+                    gradients.len(),
+                    children.len(),
+                    "custom backward must return one gradient per child"
+                );
+                for (child, gradient) in std::iter::zip(children, gradients) {
+                    child.body.borrow_mut().gradient += gradient;
+                }
+            }
+
             None => (),
         }
-        for child in &mut self.body.borrow_mut().children {
-            child.compute_gradients_recursive()
+    }
+
+    // Compute the gradient of this value w.r.t. each of `wrt` symbolically.
+    // Unlike `compute_gradients`, which stores plain floats, the gradients
+    // returned here are themselves `Value`s built out of the operation graph,
+    // so they can be differentiated again to get higher order derivatives.
+    // The same rules as `propagate_gradient` are used, expressed as
+    // `Value` operations. Values in `wrt` that this value doesn't depend on
+    // get a gradient of 0.
+    pub fn gradient_graph(&self, wrt: &[Value]) -> Vec<Value> {
+        let mut gradients: HashMap<*const RefCell<ValueBody>, Value> = HashMap::new();
+        gradients.insert(Rc::as_ptr(&self.body), constant(1.0));
+        for v in self.topological_order().iter().rev() {
+            let gradient = match gradients.get(&Rc::as_ptr(&v.body)) {
+                Some(gradient) => gradient.clone(),
+                None => continue,
+            };
+            let body = v.body.borrow();
+            let children = &body.children;
+            let child_gradients: Vec<(Value, Value)> = match &body.operation {
+                Some(Operation::Addition) | Some(Operation::Sum) => children
+                    .iter()
+                    .map(|child| (child.clone(), gradient.clone()))
+                    .collect(),
+                Some(Operation::Product) => (0..children.len())
+                    .map(|i| {
+                        let others: Vec<Value> = children
+                            .iter()
+                            .enumerate()
+                            .filter(|(j, _)| *j != i)
+                            .map(|(_, child)| child.clone())
+                            .collect();
+                        (
+                            children[i].clone(),
+                            gradient.mul(&Value::product_of(&others)),
+                        )
+                    })
+                    .collect(),
+                Some(Operation::Multiplication) => vec![
+                    (children[0].clone(), gradient.mul(&children[1])),
+                    (children[1].clone(), gradient.mul(&children[0])),
+                ],
+                Some(Operation::Linear) => {
+                    let n = children.len() / 2;
+                    let mut gradients: Vec<(Value, Value)> = (0..n)
+                        .flat_map(|i| {
+                            let (w, x) = (&children[i], &children[n + i]);
+                            [(w.clone(), gradient.mul(x)), (x.clone(), gradient.mul(w))]
+                        })
+                        .collect();
+                    gradients.push((children[2 * n].clone(), gradient.clone()));
+                    gradients
+                }
+                Some(Operation::Division) => {
+                    let (numerator, denominator) = (&children[0], &children[1]);
+                    let denominator_derivative =
+                        numerator.div(&denominator.squared()).mul(&constant(-1.0));
+                    vec![
+                        (numerator.clone(), gradient.div(denominator)),
+                        (denominator.clone(), gradient.mul(&denominator_derivative)),
+                    ]
+                }
+                Some(Operation::Power) => {
+                    let (base, exponent) = (&children[0], &children[1]);
+                    let derivative = exponent.mul(&base.pow(&exponent.subtract(&constant(1.0))));
+                    let mut gradients = vec![(base.clone(), gradient.mul(&derivative))];
+                    if base.data() > 0.0 {
+                        gradients.push((exponent.clone(), gradient.mul(v).mul(&base.ln())));
+                    }
+                    gradients
+                }
+                Some(Operation::Max) => {
+                    let winner = if children[0].data() >= children[1].data() {
+                        &children[0]
+                    } else {
+                        &children[1]
+                    };
+                    vec![(winner.clone(), gradient)]
+                }
+                // The derivative of relu is a step, so it's a constant as far as
+                // any further derivatives are concerned.
+                Some(Operation::Relu) => {
+                    let step = if children[0].data() > 0.0 { 1.0 } else { 0.0 };
+                    vec![(children[0].clone(), gradient.mul(&constant(step)))]
+                }
+                Some(Operation::Relu6) => {
+                    let data = children[0].data();
+                    let step = if data > 0.0 && data < 6.0 { 1.0 } else { 0.0 };
+                    vec![(children[0].clone(), gradient.mul(&constant(step)))]
+                }
+                Some(Operation::Sign) | Some(Operation::StopGradient) => Vec::new(),
+                // Like relu, the clamp is piecewise, so a clipped gradient is
+                // a constant.
+                Some(Operation::ClipGrad(max_abs)) => {
+                    let clipped = if gradient.data().abs() > *max_abs {
+                        constant(gradient.data().clamp(-max_abs, *max_abs))
+                    } else {
+                        gradient
+                    };
+                    vec![(children[0].clone(), clipped)]
+                }
+                Some(Operation::Exp) => vec![(children[0].clone(), gradient.mul(v))],
+                Some(Operation::Ln) => vec![(children[0].clone(), gradient.div(&children[0]))],
+                Some(Operation::Sigmoid) => {
+                    let derivative = v.mul(&constant(1.0).subtract(v));
+                    vec![(children[0].clone(), gradient.mul(&derivative))]
+                }
+                Some(Operation::Tanh) | Some(Operation::TanhFast) => {
+                    let derivative = constant(1.0).subtract(&v.squared());
+                    vec![(children[0].clone(), gradient.mul(&derivative))]
+                }
+                Some(Operation::Atan) => {
+                    let derivative = constant(1.0)
+                        .add(&children[0].squared())
+                        .pow(&constant(-1.0));
+                    vec![(children[0].clone(), gradient.mul(&derivative))]
+                }
+                Some(Operation::Sinh) => {
+                    vec![(children[0].clone(), gradient.mul(&children[0].cosh()))]
+                }
+                Some(Operation::Cosh) => {
+                    vec![(children[0].clone(), gradient.mul(&children[0].sinh()))]
+                }
+                // Symbolic gradients have to keep the graph anyway, so this
+                // just rebuilds it on the children.
+                Some(Operation::Checkpoint(f)) => {
+                    let inner = (f.0)(children).gradient_graph(children);
+                    std::iter::zip(children, inner)
+                        .map(|(child, g)| (child.clone(), gradient.mul(&g)))
+                        .collect()
+                }
+                Some(Operation::Custom(f)) => std::iter::zip(children, (f.0)(1.0))
+                    .map(|(child, derivative)| (child.clone(), gradient.mul(&constant(derivative))))
+                    .collect(),
+                None => Vec::new(),
+            };
+            for (child, child_gradient) in child_gradients {
+                let key = Rc::as_ptr(&child.body);
+                let total = match gradients.get(&key) {
+                    Some(existing) => existing.add(&child_gradient),
+                    None => child_gradient,
+                };
+                gradients.insert(key, total);
+            }
         }
+        wrt.iter()
+            .map(|w| {
+                gradients
+                    .get(&Rc::as_ptr(&w.body))
+                    .cloned()
+                    .unwrap_or_else(|| constant(0.0))
+            })
+            .collect()
     }
 
     // Move this value in the direction of the gradient proporitional to the provided
-    // `learning_rate`.
+    // `learning_rate`. This is the update step, and unlike `compute_gradients`
+    // it changes the data. Only parameters are updated, see `is_param`.
     pub fn learn(&self, learning_rate: f32) {
-        self.body.borrow_mut().data -= self.gradient() * learning_rate;
+        let mut body = self.body.borrow_mut();
+        if !body.trainable {
+            return;
+        }
+        body.data -= body.gradient * learning_rate;
+        body.dirty = true;
+    }
+
+    // Zero out all gradients in the operation graph below (and including)
+    // this value. Each distinct value is visited once, no matter how many
+    // paths lead to it.
+    pub fn zero_grad(&self) {
+        for v in self.unique_values() {
+            v.body.borrow_mut().gradient = 0.0;
+        }
+    }
+
+    // Every distinct value in the operation graph, including this one.
+    fn unique_values(&self) -> Vec<Value> {
+        let mut visited = HashSet::new();
+        let mut values = Vec::new();
+        let mut stack = vec![self.clone()];
+        while let Some(v) = stack.pop() {
+            if visited.insert(Rc::as_ptr(&v.body)) {
+                stack.extend(v.body.borrow().children.iter().cloned());
+                values.push(v);
+            }
+        }
+        values
+    }
+
+    // Every distinct value in the operation graph, ordered so that each value
+    // comes after all of the values it was produced from.
+    fn topological_order(&self) -> Vec<Value> {
+        topological_order_of(std::slice::from_ref(self))
+    }
+
+    // Rebuild the operation graph below this value, with each value whose
+    // `id` is in `leaf_map` replaced by the mapped value, e.g. to apply the
+    // same computation to new inputs. Values that depend on a replaced value
+    // are rebuilt with fresh data, while everything else, such as the other
+    // leaves, is shared with the original graph.
+    //
+    // Custom operations (see `custom`) have no forward function, so a custom
+    // value that depends on a replaced value can't be rebuilt, and panics.
+    pub fn clone_subgraph(&self, leaf_map: &HashMap<*const (), Value>) -> Value {
+        let mut clones: HashMap<*const (), Value> = HashMap::new();
+        for v in self.topological_order() {
+            let clone = match leaf_map.get(&v.id()) {
+                Some(replacement) => replacement.clone(),
+                None => {
+                    let body = v.body.borrow();
+                    let children: Vec<Value> = body
+                        .children
+                        .iter()
+                        .map(|child| clones[&child.id()].clone())
+                        .collect();
+                    let unchanged = std::iter::zip(&children, &body.children)
+                        .all(|(new, old)| new.id() == old.id());
+                    match &body.operation {
+                        Some(Operation::Custom(_)) if !unchanged => {
+                            panic!("clone_subgraph can't rebuild a custom operation on new inputs")
+                        }
+                        Some(operation) if !unchanged => {
+                            let clone = Value::from_operation(operation.clone(), children);
+                            clone.body.borrow_mut().label = body.label.clone();
+                            clone
+                        }
+                        _ => v.clone(),
+                    }
+                }
+            };
+            clones.insert(v.id(), clone);
+        }
+        clones[&self.id()].clone()
+    }
+
+    // A deep copy of the operation graph below this value. Unlike `clone`,
+    // which shares the underlying value, every value in the copy is new, so
+    // the copy and the original can be changed independently. Values shared
+    // within the graph are shared within the copy in the same way.
+    pub fn to_owned_graph(&self) -> Value {
+        let mut copies: HashMap<*const (), Value> = HashMap::new();
+        for v in self.topological_order() {
+            let body = v.body.borrow();
+            let copy = Value::new(ValueBody {
+                data: body.data,
+                children: body
+                    .children
+                    .iter()
+                    .map(|child| copies[&child.id()].clone())
+                    .collect(),
+                gradient: body.gradient,
+                operation: body.operation.clone(),
+                label: body.label.clone(),
+                dirty: body.dirty,
+                trainable: body.trainable,
+            });
+            copies.insert(v.id(), copy);
+        }
+        copies[&self.id()].clone()
+    }
+
+    // Panic if the operation graph contains a cycle, which would make the
+    // backward pass loop forever. Values can only be built from existing
+    // values, so this should never fire; it's a sanity check for new
+    // operations. The check only runs in debug builds.
+    pub fn assert_acyclic(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        // Values on the current path are `in_progress`; reaching one of them
+        // again means we've gone around a cycle. Like `topological_order`,
+        // each value is pushed a second time to be finished after its
+        // children.
+        let mut in_progress = HashSet::new();
+        let mut done = HashSet::new();
+        let mut stack = vec![(self.clone(), false)];
+        while let Some((v, expanded)) = stack.pop() {
+            let key = Rc::as_ptr(&v.body);
+            if expanded {
+                in_progress.remove(&key);
+                done.insert(key);
+                continue;
+            }
+            if done.contains(&key) {
+                continue;
+            }
+            assert!(
+                in_progress.insert(key),
+                "cycle detected in the operation graph"
+            );
+            stack.push((v.clone(), true));
+            stack.extend(
+                v.body
+                    .borrow()
+                    .children
+                    .iter()
+                    .map(|child| (child.clone(), false)),
+            );
+        }
+    }
+
+    // Find where a non-finite (NaN or infinite) value first appears in the
+    // operation graph. Values are checked children first, so the result is a
+    // value whose own inputs were all finite, i.e. the operation that
+    // overflowed or was undefined. Use `op_name` on it to see which one.
+    pub fn find_nan(&self) -> Option<Value> {
+        self.topological_order()
+            .into_iter()
+            .find(|v| !v.is_finite())
+    }
+
+    // The length of the longest path from this value to a leaf in the
+    // operation graph. Leaves have a depth of 0.
+    //
+    // Since the same value can be reached through many paths, the depth of
+    // each value is cached by its address so that every value is only
+    // visited once, rather than once per path. Values are visited in
+    // topological order, so each child's depth is known before its parents'.
+    pub fn graph_depth(&self) -> usize {
+        let mut depths: HashMap<*const RefCell<ValueBody>, usize> = HashMap::new();
+        for v in self.topological_order() {
+            let depth = v
+                .body
+                .borrow()
+                .children
+                .iter()
+                .map(|child| depths[&Rc::as_ptr(&child.body)] + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(Rc::as_ptr(&v.body), depth);
+        }
+        depths[&Rc::as_ptr(&self.body)]
+    }
+
+    // The number of distinct values in the operation graph, including this one.
+    pub fn graph_size(&self) -> usize {
+        self.unique_values().len()
+    }
+
+    // Drop the links to the values that this value was produced from, turning
+    // it into a leaf. The data (and gradient) of this value are preserved,
+    // and any part of the graph that is no longer referenced elsewhere is
+    // freed.
+    pub fn clear_graph(&self) {
+        let mut body = self.body.borrow_mut();
+        body.children.clear();
+        body.operation = None;
+    }
+
+    // Render the operation graph in the graphviz DOT format. Each value is a
+    // node showing its label (if any), data, gradient and operation, with an
+    // edge from each child to the value it produced.
+    pub fn to_dot(&self) -> String {
+        self.render_dot(|_child| String::new())
+    }
+
+    // Same as `to_dot`, but each edge is also annotated with the gradient of
+    // its child and colored by the gradient's magnitude relative to the
+    // largest in the graph, from blue for (near) zero to red for the
+    // largest. Vanishing and exploding gradients then stand out at a
+    // glance. Non-finite gradients are always red.
+    pub fn to_dot_with_grads(&self) -> String {
+        let max = self
+            .unique_values()
+            .iter()
+            .map(|v| v.gradient().abs())
+            .filter(|g| g.is_finite())
+            .fold(0.0, f32::max);
+        self.render_dot(|child| {
+            let gradient = child.gradient();
+            let scale = if !gradient.is_finite() {
+                1.0
+            } else if max > 0.0 {
+                gradient.abs() / max
+            } else {
+                0.0
+            };
+            // Graphviz accepts "hue saturation value" colors, and a hue of
+            // 0.667 is blue while 0 is red.
+            format!(
+                " [label=\"{}\", color=\"{:.3} 1.000 1.000\"]",
+                gradient,
+                0.667 * (1.0 - scale)
+            )
+        })
     }
 
-    // Zero out all gradients in the operation graph.
-    fn clear_gradients(&mut self) {
-        self.body.borrow_mut().gradient = 0.0;
-        for child in &mut self.body.borrow_mut().children {
-            child.clear_gradients();
+    // Shared by `to_dot` and `to_dot_with_grads`. `edge_attributes` returns
+    // the attributes (if any) of the edge from the given child.
+    fn render_dot(&self, edge_attributes: impl Fn(&Value) -> String) -> String {
+        let values = self.unique_values();
+        let ids: HashMap<*const RefCell<ValueBody>, usize> = values
+            .iter()
+            .enumerate()
+            .map(|(id, v)| (Rc::as_ptr(&v.body), id))
+            .collect();
+        let mut dot = String::from("digraph {\n");
+        for (id, v) in values.iter().enumerate() {
+            let mut text = format!("data={} grad={}", v.data(), v.gradient());
+            if let Some(label) = v.label() {
+                text = format!("{} | {}", label, text);
+            }
+            if let Some(op) = v.op_name() {
+                text = format!("{} | {}", text, op);
+            }
+            dot += &format!("  n{} [label=\"{}\"];\n", id, text.replace('"', "\\\""));
+            for child in &v.body.borrow().children {
+                dot += &format!(
+                    "  n{} -> n{}{};\n",
+                    ids[&Rc::as_ptr(&child.body)],
+                    id,
+                    edge_attributes(child)
+                );
+            }
         }
+        dot += "}\n";
+        dot
+    }
+
+    // Dump the operation graph, just for internal debugging purposes. Uses an
+    // explicit stack rather than recursion, so deep graphs can't overflow
+    // the call stack.
+    pub fn dump(&self) {
+        let mut stack = vec![self.clone()];
+        while let Some(v) = stack.pop() {
+            match v.label() {
+                Some(label) => println!(
+                    "{}: data = {}, gradient = {}",
+                    label,
+                    v.data(),
+                    v.gradient()
+                ),
+                None => println!("data = {}, gradient = {}", v.data(), v.gradient()),
+            }
+            stack.extend(v.body.borrow().children.iter().rev().cloned());
+        }
+    }
+}
+
+// Run a single backward pass over the combined operation graphs of
+// `outputs`, as if computing the gradients of sum(seeds[i] * outputs[i]).
+// Each output's gradient is seeded with its entry of `seeds`, so e.g. a seed
+// of 0 leaves an output out entirely. Every gradient in the combined graph
+// is reset first, like `Value::compute_gradients`.
+pub fn backward_from(outputs: &[Value], seeds: &[f32]) {
+    assert_eq!(outputs.len(), seeds.len(), "expected one seed per output");
+    let order = topological_order_of(outputs);
+    for v in &order {
+        v.set_gradient(0.0);
+    }
+    // Added rather than assigned, since the same output may be listed more
+    // than once, or be part of another output's graph.
+    for (output, seed) in std::iter::zip(outputs, seeds) {
+        output.set_gradient(output.gradient() + seed);
     }
+    for v in order.iter().rev() {
+        v.propagate_gradient();
+    }
+}
 
-    // Dump the operation graph, just for internal debugging purposes.
-    pub fn dump(&self) -> () {
-        println!("data = {}, gradient = {}", self.data(), self.gradient());
-        for child in &self.body.borrow().children {
-            child.dump();
+// Every distinct value in the operation graphs of all of `roots`, ordered
+// so that each value comes after all of the values it was produced from.
+// This is a depth first search with an explicit stack, so that very deep
+// graphs can't overflow the call stack. Each value is pushed once to expand
+// its children and once more to be emitted after them.
+fn topological_order_of(roots: &[Value]) -> Vec<Value> {
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    let mut stack: Vec<(Value, bool)> = roots
+        .iter()
+        .rev()
+        .map(|root| (root.clone(), false))
+        .collect();
+    while let Some((v, expanded)) = stack.pop() {
+        if expanded {
+            order.push(v);
+        } else if visited.insert(Rc::as_ptr(&v.body)) {
+            stack.push((v.clone(), true));
+            let body = v.body.borrow();
+            stack.extend(
+                body.children
+                    .iter()
+                    .rev()
+                    .map(|child| (child.clone(), false)),
+            );
         }
     }
+    order
 }
 
-enum Operation {
+// The Hessian-vector product of `output` w.r.t. `params`: H * `vector`, where
+// H is the matrix of second derivatives of `output`. This is computed by
+// differentiating the dot product of the symbolic gradient with `vector`,
+// so the full Hessian is never built.
+pub fn hvp(output: &Value, params: &[Value], vector: &[f32]) -> Vec<f32> {
+    assert_eq!(
+        params.len(),
+        vector.len(),
+        "expected one vector element per parameter"
+    );
+    let gradients = output.gradient_graph(params);
+    let dot = std::iter::zip(&gradients, vector)
+        .map(|(g, v)| g.mul(&constant(*v)))
+        .reduce(|x, y| x.add(&y));
+    match dot {
+        Some(dot) => dot
+            .gradient_graph(params)
+            .iter()
+            .map(|h| h.data())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Compare the gradients computed by the backward pass against central finite
+// differences. `f` builds an output from the given input values, and is
+// evaluated at `inputs`. Returns an (analytic, numerical) gradient pair for
+// each input; the two should agree up to O(epsilon^2) wherever `f` is smooth.
+pub fn grad_check(f: impl Fn(&[Value]) -> Value, inputs: &[f32], epsilon: f32) -> Vec<(f32, f32)> {
+    let leaves: Vec<Value> = inputs.iter().map(|x| value(*x)).collect();
+    f(&leaves).compute_gradients();
+    let evaluate = |i: usize, delta: f32| {
+        let perturbed: Vec<Value> = inputs
+            .iter()
+            .enumerate()
+            .map(|(j, x)| value(if i == j { x + delta } else { *x }))
+            .collect();
+        f(&perturbed).data()
+    };
+    leaves
+        .iter()
+        .enumerate()
+        .map(|(i, leaf)| {
+            let numerical = (evaluate(i, epsilon) - evaluate(i, -epsilon)) / (2.0 * epsilon);
+            (leaf.gradient(), numerical)
+        })
+        .collect()
+}
+
+// The operation that produced a `Value` from its children.
+#[derive(Clone, Debug)]
+pub enum Operation {
     Addition,
+    Sum,
     Multiplication,
+    Product,
+    Linear,
+    Division,
     Power,
+    Max,
     Relu,
+    Relu6,
+    Sign,
+    Exp,
+    Ln,
+    Sigmoid,
+    Tanh,
+    TanhFast,
+    Atan,
+    Sinh,
+    Cosh,
+    StopGradient,
+    ClipGrad(f32),
+    Checkpoint(CheckpointFn),
+    Custom(BackwardFn),
+}
+
+type GraphFn = dyn Fn(&[Value]) -> Value;
+
+// The function recomputed by a checkpoint, see `Value::checkpoint`.
+#[derive(Clone)]
+pub struct CheckpointFn(Rc<GraphFn>);
+
+impl fmt::Debug for CheckpointFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CheckpointFn")
+    }
+}
+
+// The backward function of a custom operation, see `Value::custom`.
+#[derive(Clone)]
+pub struct BackwardFn(Rc<dyn Fn(f32) -> Vec<f32>>);
+
+impl fmt::Debug for BackwardFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BackwardFn")
+    }
+}
+
+impl Operation {
+    // The result of this operation on the data of its children, or None for
+    // custom operations, which only know their backward function.
+    fn evaluate(&self, inputs: &[f32]) -> Option<f32> {
+        let data = match self {
+            Operation::Addition => inputs[0] + inputs[1],
+            Operation::Sum => inputs.iter().sum(),
+            Operation::Multiplication => inputs[0] * inputs[1],
+            Operation::Product => inputs.iter().product(),
+            Operation::Linear => {
+                let n = inputs.len() / 2;
+                std::iter::zip(&inputs[..n], &inputs[n..2 * n])
+                    .map(|(w, x)| w * x)
+                    .sum::<f32>()
+                    + inputs[2 * n]
+            }
+            Operation::Division => inputs[0] / inputs[1],
+            Operation::Power => inputs[0].powf(inputs[1]),
+            Operation::Max => inputs[0].max(inputs[1]),
+            Operation::Relu => inputs[0].max(0.0),
+            Operation::Relu6 => inputs[0].clamp(0.0, 6.0),
+            Operation::Sign => {
+                if inputs[0] > 0.0 {
+                    1.0
+                } else if inputs[0] < 0.0 {
+                    -1.0
+                } else {
+                    0.0
+                }
+            }
+            Operation::Exp => inputs[0].exp(),
+            Operation::Ln => inputs[0].ln(),
+            Operation::Sigmoid => 1.0 / (1.0 + (-inputs[0]).exp()),
+            Operation::Tanh => inputs[0].tanh(),
+            Operation::TanhFast => {
+                let x = inputs[0];
+                let x2 = x * x;
+                let numerator = x * (135135.0 + x2 * (17325.0 + x2 * (378.0 + x2)));
+                let denominator = 135135.0 + x2 * (62370.0 + x2 * (3150.0 + 28.0 * x2));
+                (numerator / denominator).clamp(-1.0, 1.0)
+            }
+            Operation::Atan => inputs[0].atan(),
+            Operation::Sinh => inputs[0].sinh(),
+            Operation::Cosh => inputs[0].cosh(),
+            Operation::StopGradient | Operation::ClipGrad(_) => inputs[0],
+            Operation::Checkpoint(f) => {
+                let leaves: Vec<Value> = inputs.iter().map(|x| constant(*x)).collect();
+                (f.0)(&leaves).data()
+            }
+            Operation::Custom(_) => return None,
+        };
+        Some(data)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Addition => "Add",
+            Operation::Sum => "Sum",
+            Operation::Multiplication => "Mul",
+            Operation::Product => "Product",
+            Operation::Linear => "Linear",
+            Operation::Division => "Div",
+            Operation::Power => "Pow",
+            Operation::Max => "Max",
+            Operation::Relu => "Relu",
+            Operation::Relu6 => "Relu6",
+            Operation::Sign => "Sign",
+            Operation::Exp => "Exp",
+            Operation::Ln => "Ln",
+            Operation::Sigmoid => "Sigmoid",
+            Operation::Tanh => "Tanh",
+            Operation::TanhFast => "TanhFast",
+            Operation::Atan => "Atan",
+            Operation::Sinh => "Sinh",
+            Operation::Cosh => "Cosh",
+            Operation::StopGradient => "StopGradient",
+            Operation::ClipGrad(_) => "ClipGrad",
+            Operation::Checkpoint(_) => "Checkpoint",
+            Operation::Custom(_) => "Custom",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 struct ValueBody {
@@ -210,4 +1436,694 @@ struct ValueBody {
     children: Vec<Value>,
     gradient: f32,
     operation: Option<Operation>,
+    label: Option<String>,
+    // Set when the data is changed directly, e.g. by `set_data`, so that
+    // `recompute` knows which parts of the graph are stale.
+    dirty: bool,
+    // Whether `learn` and the optimizers may update this value.
+    trainable: bool,
+}
+
+// Dropping a value drops its children, which would otherwise recurse once
+// per level of the graph and overflow the stack on very deep graphs.
+// Instead, children that are only owned by this value are unlinked onto an
+// explicit stack and dropped one at a time.
+impl Drop for ValueBody {
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(v) = stack.pop() {
+            if let Ok(cell) = Rc::try_unwrap(v.body) {
+                stack.append(&mut cell.into_inner().children);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_values_and_gradient() {
+        for (x, expected) in [(-2.5, -1.0), (0.0, 0.0), (3.0, 1.0)] {
+            let x = value(x);
+            let y = x.sign();
+            y.compute_gradients();
+            assert_eq!(y.data(), expected);
+            assert_eq!(x.gradient(), 0.0);
+        }
+    }
+
+    #[test]
+    fn is_finite_detects_overflow_and_nan() {
+        let large = value(1e30);
+        assert!(large.is_finite());
+        let overflow = large.mul(&large);
+        assert!(!overflow.is_finite());
+        assert!(!overflow.subtract(&overflow).is_finite());
+    }
+
+    #[test]
+    fn exp_and_ln_outside_their_range_are_not_finite() {
+        assert!(value(1.0).exp().is_finite());
+        assert!(!value(1000.0).exp().is_finite());
+        assert!(!value(-1.0).ln().is_finite());
+    }
+
+    #[test]
+    fn atan_values_and_gradient() {
+        assert!((value(1.0).atan().data() - std::f32::consts::FRAC_PI_4).abs() < 1e-6);
+        let x = value(0.0);
+        x.atan().compute_gradients();
+        assert_eq!(x.gradient(), 1.0);
+    }
+
+    #[test]
+    fn sinh_and_cosh_values() {
+        assert_eq!(value(0.0).sinh().data(), 0.0);
+        assert_eq!(value(0.0).cosh().data(), 1.0);
+        for x in [-2.0, -0.5, 0.3, 1.5] {
+            let (sinh, cosh) = (value(x).sinh().data(), value(x).cosh().data());
+            assert!((cosh * cosh - sinh * sinh - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn graph_depth_of_known_graphs() {
+        let x = value(1.0);
+        assert_eq!(x.graph_depth(), 0);
+        // The longest path goes through the three chained operations, even
+        // though `x` is also added directly.
+        let chain = x.exp().tanh().mul(&value(2.0));
+        assert_eq!(chain.add(&x).graph_depth(), 4);
+
+        // A chain of diamonds has 2^n paths but is still quick to measure.
+        let mut v = x;
+        for _ in 0..100 {
+            v = v.add(&v);
+        }
+        assert_eq!(v.graph_depth(), 100);
+    }
+
+    #[test]
+    fn clear_graph_keeps_the_data() {
+        let out = value(2.0).mul(&value(3.0)).add(&value(1.0));
+        out.compute_gradients();
+        assert_eq!(out.graph_size(), 5);
+        out.clear_graph();
+        assert_eq!(out.graph_size(), 1);
+        assert_eq!(out.data(), 7.0);
+        assert_eq!(out.gradient(), 1.0);
+        assert_eq!(out.op_name(), None);
+    }
+
+    #[test]
+    fn zero_grad_visits_shared_values_once() {
+        // 2^200 paths lead from the root to `x`, so this only finishes if
+        // each value is visited once.
+        let x = value(1.0);
+        let mut v = x.mul(&value(1.0));
+        for _ in 0..200 {
+            v = v.add(&v).mul(&constant(0.5));
+        }
+        v.compute_gradients();
+        assert_eq!(x.gradient(), 1.0);
+        v.zero_grad();
+        assert!(v.unique_values().iter().all(|v| v.gradient() == 0.0));
+    }
+
+    #[test]
+    fn op_names() {
+        let (x, y) = (value(1.0), value(2.0));
+        assert_eq!(x.op_name(), None);
+        assert_eq!(x.add(&y).op_name(), Some("Add"));
+        assert_eq!(x.mul(&y).op_name(), Some("Mul"));
+        assert_eq!(x.pow(&y).op_name(), Some("Pow"));
+        assert_eq!(x.relu().op_name(), Some("Relu"));
+        // Composite operations are named after their last step.
+        assert_eq!(x.subtract(&y).op_name(), Some("Add"));
+    }
+
+    #[test]
+    fn operation_display_strings() {
+        let checkpoint = CheckpointFn(Rc::new(|v: &[Value]| v[0].clone()));
+        let backward = BackwardFn(Rc::new(|g: f32| vec![g]));
+        let operations = [
+            (Operation::Addition, "Add"),
+            (Operation::Sum, "Sum"),
+            (Operation::Multiplication, "Mul"),
+            (Operation::Product, "Product"),
+            (Operation::Linear, "Linear"),
+            (Operation::Division, "Div"),
+            (Operation::Power, "Pow"),
+            (Operation::Max, "Max"),
+            (Operation::Relu, "Relu"),
+            (Operation::Relu6, "Relu6"),
+            (Operation::Sign, "Sign"),
+            (Operation::Exp, "Exp"),
+            (Operation::Ln, "Ln"),
+            (Operation::Sigmoid, "Sigmoid"),
+            (Operation::Tanh, "Tanh"),
+            (Operation::TanhFast, "TanhFast"),
+            (Operation::Atan, "Atan"),
+            (Operation::Sinh, "Sinh"),
+            (Operation::Cosh, "Cosh"),
+            (Operation::StopGradient, "StopGradient"),
+            (Operation::ClipGrad(1.0), "ClipGrad"),
+            (Operation::Checkpoint(checkpoint), "Checkpoint"),
+            (Operation::Custom(backward), "Custom"),
+        ];
+        for (operation, name) in operations {
+            assert_eq!(operation.to_string(), name);
+        }
+        assert_eq!(
+            value(1.0).tanh().operation().map(|op| op.to_string()),
+            Some("Tanh".to_string())
+        );
+    }
+
+    #[test]
+    fn labels_appear_in_dot_output() {
+        let x = labeled(2.0, "weight");
+        let out = x.mul(&constant(3.0));
+        out.set_label("out");
+        let dot = out.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains("weight | data=2"));
+        assert!(dot.contains("out | data=6"));
+        assert_eq!(dot.matches(" -> ").count(), 2);
+    }
+
+    #[test]
+    fn second_derivatives() {
+        // d/dx x^3 = 3x^2 and d^2/dx^2 x^3 = 6x, so 12 and 12 at x = 2.
+        let x = value(2.0);
+        let first = x
+            .pow(&constant(3.0))
+            .gradient_graph(std::slice::from_ref(&x))
+            .remove(0);
+        assert!((first.data() - 12.0).abs() < 1e-5);
+        let second = first.gradient_graph(std::slice::from_ref(&x)).remove(0);
+        assert!((second.data() - 12.0).abs() < 1e-4);
+
+        // For f = x^2 * y, H = [[2y, 2x], [2x, 0]].
+        let (x, y) = (value(1.5), value(-2.0));
+        let f = x.squared().mul(&y);
+        let h = hvp(&f, &[x, y], &[1.0, 2.0]);
+        assert!((h[0] - (2.0 * -2.0 + 2.0 * 1.5 * 2.0)).abs() < 1e-4);
+        assert!((h[1] - 2.0 * 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn grad_of_a_product() {
+        let (x, y) = (value(3.0), value(4.0));
+        let out = x.mul(&y);
+        assert_eq!(out.grad_of(&x), 4.0);
+        assert_eq!(out.grad_of(&y), 3.0);
+        // Identified by identity: a value with the same data isn't part of
+        // the graph.
+        assert_eq!(out.grad_of(&value(3.0)), 0.0);
+    }
+
+    #[test]
+    fn relu6_gradient_regimes() {
+        for (x, data, gradient) in [(-1.0, 0.0, 0.0), (3.0, 3.0, 1.0), (8.0, 6.0, 0.0)] {
+            let x = value(x);
+            let y = x.relu6();
+            y.compute_gradients();
+            assert_eq!(y.data(), data);
+            assert_eq!(x.gradient(), gradient);
+        }
+    }
+
+    #[test]
+    fn silu_matches_finite_differences() {
+        let points: &[&[f32]] = &[&[-4.0], &[-1.0], &[0.0], &[0.7], &[3.0]];
+        assert_gradients_match(|v| v[0].silu(), points);
+        // silu'(0) = sigmoid(0) = 0.5
+        let x = value(0.0);
+        x.silu().compute_gradients();
+        assert_eq!(x.gradient(), 0.5);
+    }
+
+    // Run `grad_check` on `f` at each of `points`, and assert that the
+    // analytic and numerical gradients agree.
+    fn assert_gradients_match(f: impl Fn(&[Value]) -> Value, points: &[&[f32]]) {
+        for inputs in points {
+            for (analytic, numerical) in grad_check(&f, inputs, 1e-3) {
+                assert!(
+                    (analytic - numerical).abs() <= 1e-2 * numerical.abs().max(1.0),
+                    "analytic {} != numerical {} at {:?}",
+                    analytic,
+                    numerical,
+                    inputs
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic_gradients() {
+        let points: &[&[f32]] = &[&[1.0, 2.0], &[-3.0, 0.5], &[0.25, -1.5]];
+        assert_gradients_match(|v| v[0].add(&v[1]), points);
+        assert_gradients_match(|v| v[0].subtract(&v[1]), points);
+        assert_gradients_match(|v| v[0].mul(&v[1]), points);
+        assert_gradients_match(|v| v[0].div(&v[1]), points);
+        assert_gradients_match(|v| v[0].lerp(&v[1], 0.3), points);
+        assert_gradients_match(|v| v[0].squared(), points);
+    }
+
+    #[test]
+    fn variadic_gradients() {
+        let points: &[&[f32]] = &[&[1.0, 2.0, -0.5, 3.0, 0.1], &[0.0, -1.0, 2.0, 0.5, -2.0]];
+        assert_gradients_match(Value::sum_of, points);
+        assert_gradients_match(Value::product_of, points);
+        assert_gradients_match(|v| Value::linear(&v[0..2], &v[2..4], &v[4]), points);
+    }
+
+    #[test]
+    fn power_gradients() {
+        let points: &[&[f32]] = &[&[2.0, 3.0], &[0.5, 1.5], &[1.5, -2.0]];
+        assert_gradients_match(|v| v[0].pow(&v[1]), points);
+    }
+
+    #[test]
+    fn piecewise_gradients_near_kinks() {
+        let points: &[&[f32]] = &[&[-1.0], &[-0.01], &[0.01], &[2.0], &[5.99], &[6.01], &[7.0]];
+        assert_gradients_match(|v| v[0].relu(), points);
+        assert_gradients_match(|v| v[0].relu6(), points);
+        assert_gradients_match(|v| v[0].sign(), points);
+        assert_gradients_match(|v| v[0].abs(), points);
+        assert_gradients_match(|v| v[0].exp_clamped(0.0), points);
+
+        let pairs: &[&[f32]] = &[&[1.0, 2.0], &[2.0, 1.0], &[1.0, 1.01], &[1.01, 1.0]];
+        assert_gradients_match(|v| v[0].max(&v[1]), pairs);
+    }
+
+    #[test]
+    fn smooth_unary_gradients() {
+        let points: &[&[f32]] = &[&[-2.0], &[-0.5], &[0.0], &[0.5], &[2.0]];
+        assert_gradients_match(|v| v[0].exp(), points);
+        assert_gradients_match(|v| v[0].sigmoid(), points);
+        assert_gradients_match(|v| v[0].tanh(), points);
+        assert_gradients_match(|v| v[0].tanh_fast(), points);
+        assert_gradients_match(|v| v[0].silu(), points);
+        assert_gradients_match(|v| v[0].atan(), points);
+        assert_gradients_match(|v| v[0].sinh(), points);
+        assert_gradients_match(|v| v[0].cosh(), points);
+
+        let positive: &[&[f32]] = &[&[0.5], &[1.0], &[3.0]];
+        assert_gradients_match(|v| v[0].ln(), positive);
+    }
+
+    #[test]
+    fn gradient_control_operations() {
+        let points: &[&[f32]] = &[&[-1.5], &[0.5], &[2.0]];
+        // A clip that is never reached passes the gradient through.
+        assert_gradients_match(|v| v[0].squared().clip_grad(100.0), points);
+
+        let x = value(2.0);
+        x.clip_grad(1.0).mul(&constant(5.0)).compute_gradients();
+        assert_eq!(x.gradient(), 1.0);
+
+        // Only the path that doesn't go through stop_gradient contributes.
+        let x = value(3.0);
+        x.stop_gradient().mul(&x).compute_gradients();
+        assert_eq!(x.gradient(), 3.0);
+    }
+
+    #[test]
+    fn checkpoint_and_custom_gradients() {
+        let points: &[&[f32]] = &[&[1.0, 2.0], &[-0.5, 0.3]];
+        assert_gradients_match(|v| Value::checkpoint(v, |v| v[0].mul(&v[1]).tanh()), points);
+        assert_gradients_match(
+            |v| {
+                let (x, y) = (v[0].data(), v[1].data());
+                Value::custom(x * y, v.to_vec(), Box::new(move |g| vec![y * g, x * g]))
+            },
+            points,
+        );
+    }
+
+    #[test]
+    fn shared_node_gradients() {
+        let points: &[&[f32]] = &[&[1.0, 2.0], &[-0.5, 0.3], &[0.0, 1.0]];
+        assert_gradients_match(
+            |v| {
+                let shared = v[0].mul(&v[1]);
+                shared
+                    .mul(&shared)
+                    .add(&shared.tanh())
+                    .add(&v[0].sigmoid().mul(&shared))
+            },
+            points,
+        );
+    }
+
+    #[test]
+    fn find_nan_reports_the_first_bad_operation() {
+        let x = value(-1.0);
+        let out = x.ln().mul(&value(2.0)).add(&value(1.0));
+        let nan = out.find_nan().unwrap();
+        assert_eq!(nan.op_name(), Some("Ln"));
+        assert!(value(1.0).ln().find_nan().is_none());
+    }
+
+    #[test]
+    fn stopped_heads_leave_the_trunk_alone() {
+        let w = value(2.0);
+        let trunk = w.mul(&constant(3.0));
+        let (head, stopped_head) = (trunk.squared(), trunk.stop_gradient().squared());
+        stopped_head.compute_gradients();
+        assert_eq!(stopped_head.data(), 36.0);
+        assert_eq!(w.gradient(), 0.0);
+        head.add(&stopped_head).compute_gradients();
+        // Only the first head: 2 * trunk * 3
+        assert_eq!(w.gradient(), 36.0);
+    }
+
+    #[test]
+    fn gradients_accumulate_until_zeroed() {
+        let w = param(1.0);
+        let micro_batches = |w: &Value| [w.mul(&constant(2.0)), w.squared()];
+        for loss in micro_batches(&w) {
+            loss.compute_gradients_with(true);
+        }
+        // 2 from the first micro-batch plus 2w from the second.
+        assert_eq!(w.gradient(), 4.0);
+        w.learn(0.1);
+        assert!((w.data() - 0.6).abs() < 1e-6);
+
+        w.zero_grad();
+        assert_eq!(w.gradient(), 0.0);
+        for loss in micro_batches(&w) {
+            loss.compute_gradients_with(true);
+        }
+        assert!((w.gradient() - 3.2).abs() < 1e-6);
+
+        // Without accumulation, each backward pass starts over.
+        w.squared().compute_gradients();
+        assert!((w.gradient() - 1.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn negative_bases_with_integer_exponents() {
+        for (base, exponent, data, gradient) in [
+            (-2.0, 3.0, -8.0, 12.0),
+            (-2.0, 2.0, 4.0, -4.0),
+            (-3.0, -1.0, -1.0 / 3.0, -1.0 / 9.0),
+        ] {
+            let (x, p) = (value(base), constant(exponent));
+            let y = x.pow(&p);
+            y.compute_gradients();
+            assert!((y.data() - data).abs() < 1e-6);
+            assert!((x.gradient() - gradient).abs() < 1e-6);
+            assert_eq!(p.gradient(), 0.0);
+        }
+        assert_gradients_match(|v| v[0].pow(&constant(3.0)), &[&[-2.0], &[-0.5]]);
+        assert!(value(-2.0).pow(&constant(0.5)).data().is_nan());
+    }
+
+    #[test]
+    fn sum_hands_every_child_the_gradient() {
+        let children: Vec<Value> = [1.0, 2.0, 3.0, 4.0].into_iter().map(value).collect();
+        let sum = Value::sum_of(&children);
+        assert_eq!(sum.data(), 10.0);
+        assert_eq!(sum.graph_depth(), 1);
+        sum.mul(&constant(3.0)).compute_gradients();
+        assert!(children.iter().all(|c| c.gradient() == 3.0));
+    }
+
+    #[test]
+    fn product_gradients_with_and_without_a_zero() {
+        let gradients_of = |data: [f32; 3]| {
+            let children: Vec<Value> = data.into_iter().map(value).collect();
+            let product = Value::product_of(&children);
+            product.compute_gradients();
+            (
+                product.data(),
+                children.iter().map(|c| c.gradient()).collect::<Vec<f32>>(),
+            )
+        };
+        assert_eq!(gradients_of([2.0, 3.0, 4.0]), (24.0, vec![12.0, 8.0, 6.0]));
+        // Only the zero child gets a gradient, the product of the others.
+        assert_eq!(gradients_of([2.0, 0.0, 4.0]), (0.0, vec![0.0, 8.0, 0.0]));
+    }
+
+    #[test]
+    fn tanh_fast_stays_close_to_tanh() {
+        for i in 0..=1000 {
+            let x = -5.0 + i as f32 * 0.01;
+            let error = (value(x).tanh_fast().data() - x.tanh()).abs();
+            assert!(error < 1e-4, "error {} at {}", error, x);
+        }
+        assert_eq!(value(100.0).tanh_fast().data(), 1.0);
+    }
+
+    #[test]
+    fn normal_graphs_are_acyclic() {
+        let x = value(1.0);
+        let shared = x.mul(&x);
+        shared.add(&shared).tanh().add(&x).assert_acyclic();
+    }
+
+    // The check only runs in debug builds.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "cycle detected in the operation graph")]
+    fn assert_acyclic_detects_cycles() {
+        let x = value(1.0);
+        let y = x.add(&value(2.0));
+        // Values can't be built into a cycle through the public API, so fake
+        // one by making `x` depend on `y`.
+        x.body.borrow_mut().children.push(y.clone());
+        y.assert_acyclic();
+    }
+
+    #[test]
+    fn backward_only_writes_gradients() {
+        let (x, y) = (value(1.5), value(-0.5));
+        let shared = x.mul(&y).tanh();
+        let out = Value::checkpoint(&[shared.clone(), x.clone()], |v| v[0].add(&v[1]).exp())
+            .add(&shared.squared().div(&y))
+            .max(&x.relu6().pow(&constant(2.0)));
+        let values = out.unique_values();
+        let before: Vec<f32> = values.iter().map(|v| v.data()).collect();
+        out.compute_gradients();
+        out.compute_gradients_scaled(10.0);
+        backward_from(&[out.clone(), shared.clone()], &[1.0, 2.0]);
+        let after: Vec<f32> = values.iter().map(|v| v.data()).collect();
+        assert_eq!(before, after);
+        assert!(x.gradient() != 0.0 && y.gradient() != 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "loss scale must be finite and non-zero")]
+    fn loss_scale_must_be_non_zero() {
+        value(2.0).exp().compute_gradients_scaled(0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "loss scale must be finite and non-zero")]
+    fn loss_scale_must_be_finite() {
+        value(2.0).exp().compute_gradients_scaled(f32::INFINITY);
+    }
+
+    #[test]
+    fn dot_edges_are_annotated_with_gradients() {
+        let (x, y) = (value(2.0), value(3.0));
+        let out = x.mul(&y);
+        out.compute_gradients();
+        let dot = out.to_dot_with_grads();
+        // The largest gradient is red and the others bluer.
+        assert!(dot.contains("[label=\"3\", color=\"0.000 1.000 1.000\"]"));
+        assert!(dot.contains("[label=\"2\", color=\"0.222 1.000 1.000\"]"));
+        assert_eq!(dot.matches(" -> ").count(), 2);
+        assert!(!out.to_dot().contains("color="));
+    }
+
+    #[test]
+    fn recompute_skips_unaffected_values() {
+        let (a, b, c) = (value(1.0), value(2.0), value(3.0));
+        let left = a.mul(&b);
+        let right = c.exp();
+        let out = left.add(&right);
+        assert_eq!(out.recompute(), 0);
+        a.set_data(4.0);
+        // Only `left` and `out` depend on `a`.
+        assert_eq!(out.recompute(), 2);
+        assert_eq!(left.data(), 8.0);
+        assert_eq!(out.data(), 8.0 + 3.0f32.exp());
+        assert_eq!(out.recompute(), 0);
+    }
+
+    #[test]
+    fn backward_from_weights_each_output() {
+        let (x, y) = (value(2.0), value(3.0));
+        let first = x.mul(&y);
+        let second = x.add(&y);
+        backward_from(&[first, second], &[2.0, -1.0]);
+        // d/dx (2xy - (x + y)) = 2y - 1, and d/dy = 2x - 1.
+        assert_eq!(x.gradient(), 5.0);
+        assert_eq!(y.gradient(), 3.0);
+    }
+
+    #[test]
+    fn learn_only_updates_params() {
+        let w = param(2.0);
+        let x = constant(3.0);
+        let out = w.mul(&x);
+        out.compute_gradients();
+        for v in [&w, &x, &out] {
+            v.learn(0.1);
+        }
+        assert!((w.data() - 1.7).abs() < 1e-6);
+        assert_eq!(x.data(), 3.0);
+        assert_eq!(out.data(), 6.0);
+        assert!(w.is_param() && !x.is_param() && !out.is_param());
+    }
+
+    #[test]
+    fn clone_subgraph_substitutes_leaves() {
+        let (x, y) = (value(2.0), value(3.0));
+        let out = x.mul(&y).add(&y);
+        let new_x = value(5.0);
+        let leaf_map = HashMap::from([(x.id(), new_x.clone())]);
+        let copy = out.clone_subgraph(&leaf_map);
+        assert_eq!(copy.data(), 18.0);
+        assert_eq!(out.data(), 9.0);
+        copy.compute_gradients();
+        assert_eq!(new_x.gradient(), 3.0);
+        assert_eq!(y.gradient(), 6.0);
+        assert_eq!(x.gradient(), 0.0);
+    }
+
+    #[test]
+    fn checkpoint_gradients_match_the_full_graph() {
+        let f = |v: &[Value]| v[0].mul(&v[1]).tanh().add(&v[0].exp());
+        let full_inputs = [value(0.5), value(-1.5)];
+        let full = f(&full_inputs);
+        full.compute_gradients();
+        let inputs = [value(0.5), value(-1.5)];
+        let checkpointed = Value::checkpoint(&inputs, f);
+        checkpointed.compute_gradients();
+        assert_eq!(checkpointed.data(), full.data());
+        for (a, b) in std::iter::zip(&inputs, &full_inputs) {
+            assert!((a.gradient() - b.gradient()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn clip_grad_only_clamps_the_backward_pass() {
+        let x = value(3.0);
+        let clipped = x.clip_grad(0.5);
+        assert_eq!(clipped.data(), 3.0);
+        let out = clipped.mul(&constant(4.0));
+        out.compute_gradients();
+        assert_eq!(clipped.gradient(), 4.0);
+        assert_eq!(x.gradient(), 0.5);
+        let out = x.clip_grad(0.5).mul(&constant(-4.0));
+        out.compute_gradients();
+        assert_eq!(x.gradient(), -0.5);
+    }
+
+    #[test]
+    fn deep_chains_do_not_overflow_the_stack() {
+        let x = value(1.0);
+        let mut out = x.clone();
+        for _ in 0..10_000 {
+            out = out.add(&constant(1.0));
+        }
+        assert_eq!(out.data(), 10_001.0);
+        out.compute_gradients();
+        assert_eq!(x.gradient(), 1.0);
+        assert_eq!(out.graph_depth(), 10_000);
+        drop(out);
+    }
+
+    #[test]
+    fn custom_square() {
+        let x = value(3.0);
+        let d = x.data();
+        let y = Value::custom(d * d, vec![x.clone()], Box::new(move |g| vec![2.0 * d * g]));
+        y.mul(&constant(2.0)).compute_gradients();
+        assert_eq!(y.data(), 9.0);
+        assert_eq!(x.gradient(), 12.0);
+        assert_eq!(y.op_name(), Some("Custom"));
+    }
+
+    #[test]
+    fn clone_subgraph_shares_independent_custom_values() {
+        let (x, y) = (value(2.0), value(3.0));
+        let d = y.data();
+        let square = Value::custom(d * d, vec![y.clone()], Box::new(move |g| vec![2.0 * d * g]));
+        let out = x.mul(&square);
+        let leaf_map = HashMap::from([(x.id(), value(5.0))]);
+        assert_eq!(out.clone_subgraph(&leaf_map).data(), 45.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't rebuild a custom operation")]
+    fn clone_subgraph_rejects_custom_values_on_new_inputs() {
+        let x = value(2.0);
+        let d = x.data();
+        let square = Value::custom(d * d, vec![x.clone()], Box::new(move |g| vec![2.0 * d * g]));
+        let leaf_map = HashMap::from([(x.id(), value(5.0))]);
+        square.clone_subgraph(&leaf_map);
+    }
+
+    #[test]
+    fn owned_graphs_are_independent_of_the_original() {
+        let (x, y) = (value(2.0), value(3.0));
+        let out = x.mul(&y).add(&x);
+        let copy = out.to_owned_graph();
+        x.set_data(10.0);
+        out.recompute();
+        out.compute_gradients();
+        assert_eq!(out.data(), 40.0);
+        assert_eq!(copy.data(), 8.0);
+        assert_eq!(copy.gradient(), 0.0);
+        copy.compute_gradients();
+        assert_eq!(copy.recompute(), 0);
+        assert_eq!(copy.data(), 8.0);
+        assert_eq!(x.gradient(), 4.0);
+        assert_eq!(copy.graph_depth(), out.graph_depth());
+    }
+
+    #[test]
+    fn exp_clamped_saturates_large_logits() {
+        let logit = value(1000.0);
+        let out = logit.exp_clamped(20.0);
+        out.compute_gradients();
+        assert!(out.is_finite());
+        assert_eq!(out.data(), 20.0f32.exp());
+        assert_eq!(logit.gradient(), 0.0);
+        assert!(!logit.exp().is_finite());
+    }
+
+    #[test]
+    #[should_panic(expected = "only leaves can be made trainable")]
+    fn set_trainable_rejects_operations() {
+        value(1.0).add(&value(2.0)).set_trainable(true);
+    }
+
+    #[test]
+    fn param_gradients_skip_subgraphs_without_params() {
+        let (w, x, frozen) = (param(2.0), constant(0.3), param(0.5));
+        frozen.set_trainable(false);
+        // 40 operations that only depend on constants.
+        let mut features = x.clone();
+        for _ in 0..20 {
+            features = features.mul(&frozen).add(&x);
+        }
+        let out = w.mul(&features).squared();
+        out.compute_gradients();
+        let (w_gradient, frozen_gradient) = (w.gradient(), frozen.gradient());
+        assert!(frozen_gradient != 0.0);
+
+        // Only the square and the multiplication by `w` lead to a param.
+        assert_eq!(out.compute_param_gradients(), 2);
+        assert_eq!(w.gradient(), w_gradient);
+        assert_eq!(frozen.gradient(), 0.0);
+    }
 }